@@ -0,0 +1,77 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+// Throughput of the permessage-deflate extension for small messages.
+//
+// Run with: cargo bench --features deflate --bench deflate
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use soketto::{
+	base::{Header, OpCode},
+	extension::{deflate::Deflate, Extension, Param},
+	Mode, Storage,
+};
+
+/// Negotiate a client and server extension, with or without context takeover.
+fn negotiate(context_takeover: bool) -> (Deflate, Deflate) {
+	let mut client = Deflate::new(Mode::Client);
+	client.set_server_no_context_takeover(!context_takeover);
+	client.set_client_no_context_takeover(!context_takeover);
+	let mut server = Deflate::new(Mode::Server);
+	server.configure(&client.params().iter().cloned().map(Param::acquire).collect::<Vec<_>>()).unwrap();
+	client.configure(&server.params().iter().cloned().map(Param::acquire).collect::<Vec<_>>()).unwrap();
+	(client, server)
+}
+
+/// A JSON-RPC like text message of roughly the given size.
+fn message(size: usize) -> Vec<u8> {
+	let mut message = br#"{"jsonrpc":"2.0","method":"notify","params":["#.to_vec();
+	let mut i = 0;
+	while message.len() < size - 2 {
+		message.extend_from_slice(format!("{},", i * 7919 % 100_000).as_bytes());
+		i += 1
+	}
+	message.truncate(size - 2);
+	message.extend_from_slice(b"]}");
+	message
+}
+
+fn small_messages(c: &mut Criterion) {
+	let mut group = c.benchmark_group("deflate");
+	for context_takeover in [false, true] {
+		let mode = if context_takeover { "context-takeover" } else { "no-context-takeover" };
+		for size in [64, 512, 4096] {
+			let message = message(size);
+			group.throughput(Throughput::Bytes(size as u64));
+			group.bench_with_input(BenchmarkId::new(format!("encode/{}", mode), size), &message, |b, message| {
+				let (mut client, _) = negotiate(context_takeover);
+				b.iter(|| {
+					let mut header = Header::new(OpCode::Text);
+					let mut data = Storage::Shared(message);
+					client.encode(&mut header, &mut data).unwrap();
+					data
+				})
+			});
+			group.bench_with_input(BenchmarkId::new(format!("roundtrip/{}", mode), size), &message, |b, message| {
+				let (mut client, mut server) = negotiate(context_takeover);
+				b.iter(|| {
+					let mut header = Header::new(OpCode::Text);
+					let mut data = Storage::Shared(message);
+					client.encode(&mut header, &mut data).unwrap();
+					let mut data = data.as_ref().to_vec();
+					server.decode(&mut header, &mut data).unwrap();
+					data
+				})
+			});
+		}
+	}
+	group.finish()
+}
+
+criterion_group!(benches, small_messages);
+criterion_main!(benches);
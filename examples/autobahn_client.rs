@@ -38,7 +38,7 @@ async fn num_of_cases() -> Result<usize, BoxedError> {
 	let socket = TcpStream::connect("127.0.0.1:9001").await?;
 	let mut client = new_client(socket, "/getCaseCount");
 	assert!(matches!(client.handshake().await?, handshake::ServerResponse::Accepted { .. }));
-	let (_, mut receiver) = client.into_builder().finish();
+	let (_, mut receiver) = client.into_builder()?.finish();
 	let mut data = Vec::new();
 	let kind = receiver.receive_data(&mut data).await?;
 	assert!(kind.is_text());
@@ -53,7 +53,7 @@ async fn run_case(n: usize) -> Result<(), BoxedError> {
 	let socket = TcpStream::connect("127.0.0.1:9001").await?;
 	let mut client = new_client(socket, &resource);
 	assert!(matches!(client.handshake().await?, handshake::ServerResponse::Accepted { .. }));
-	let (mut sender, mut receiver) = client.into_builder().finish();
+	let (mut sender, mut receiver) = client.into_builder()?.finish();
 	let mut message = Vec::new();
 	loop {
 		message.clear();
@@ -80,7 +80,7 @@ async fn update_report() -> Result<(), BoxedError> {
 	let socket = TcpStream::connect("127.0.0.1:9001").await?;
 	let mut client = new_client(socket, &resource);
 	assert!(matches!(client.handshake().await?, handshake::ServerResponse::Accepted { .. }));
-	client.into_builder().finish().0.close().await?;
+	client.into_builder()?.finish().0.close().await?;
 	Ok(())
 }
 
@@ -31,7 +31,7 @@ async fn main() -> Result<(), BoxedError> {
 		};
 		let accept = handshake::server::Response::Accept { key, protocol: None };
 		server.send_response(&accept).await?;
-		let (mut sender, mut receiver) = server.into_builder().finish();
+		let (mut sender, mut receiver) = server.into_builder()?.finish();
 		let mut message = Vec::new();
 		loop {
 			message.clear();
@@ -125,7 +125,7 @@ async fn websocket_echo_messages(server: Server, req: Request<hyper::body::Incom
 	let stream = BufReader::new(BufWriter::new(io.compat()));
 
 	// Get back a reader and writer that we can use to send and receive websocket messages.
-	let (mut sender, mut receiver) = server.into_builder(stream).finish();
+	let (mut sender, mut receiver) = server.into_builder(stream)?.finish();
 
 	// Echo any received messages back to the client:
 	let mut message = Vec::new();
@@ -17,8 +17,16 @@
 //!
 //! [base]: https://tools.ietf.org/html/rfc6455#section-5.2
 
+#[cfg(any(feature = "tokio-codec", feature = "asynchronous-codec"))]
+mod framed;
+
 use crate::{as_u64, Parsing};
-use std::{fmt, io};
+use base64::Engine;
+use bytes::{Buf, BytesMut};
+use core::fmt;
+use sha1::{Digest, Sha1};
+#[cfg(feature = "std")]
+use std::io;
 
 /// Max. size of a frame header.
 pub(crate) const MAX_HEADER_SIZE: usize = 14;
@@ -68,28 +76,35 @@ pub enum OpCode {
 impl OpCode {
 	/// Is this a control opcode?
 	pub fn is_control(self) -> bool {
-		if let OpCode::Close | OpCode::Ping | OpCode::Pong = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+	}
+
+	/// Is this one of the opcodes 11 to 15 reserved for further control frames?
+	///
+	/// Frames with these opcodes follow the rules of control frames, i.e.
+	/// they must not be fragmented and their payload is limited to 125 bytes.
+	pub fn is_reserved_control(self) -> bool {
+		matches!(
+			self,
+			OpCode::Reserved11 | OpCode::Reserved12 | OpCode::Reserved13 | OpCode::Reserved14 | OpCode::Reserved15
+		)
 	}
 
 	/// Is this opcode reserved?
 	pub fn is_reserved(self) -> bool {
-		match self {
+		matches!(
+			self,
 			OpCode::Reserved3
-			| OpCode::Reserved4
-			| OpCode::Reserved5
-			| OpCode::Reserved6
-			| OpCode::Reserved7
-			| OpCode::Reserved11
-			| OpCode::Reserved12
-			| OpCode::Reserved13
-			| OpCode::Reserved14
-			| OpCode::Reserved15 => true,
-			_ => false,
-		}
+				| OpCode::Reserved4
+				| OpCode::Reserved5
+				| OpCode::Reserved6
+				| OpCode::Reserved7
+				| OpCode::Reserved11
+				| OpCode::Reserved12
+				| OpCode::Reserved13
+				| OpCode::Reserved14
+				| OpCode::Reserved15
+		)
 	}
 }
 
@@ -127,6 +142,7 @@ impl fmt::Display for UnknownOpCode {
 	}
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for UnknownOpCode {}
 
 impl TryFrom<u8> for OpCode {
@@ -305,6 +321,27 @@ impl Header {
 	}
 }
 
+// Frame //////////////////////////////////////////////////////////////////////////////////////////
+
+/// A complete websocket frame, i.e. a header and its payload data.
+///
+/// The payload data is never masked. If the header is marked as masked,
+/// the mask is applied when the frame is encoded.
+#[derive(Debug, Clone)]
+pub struct Frame {
+	/// The frame header.
+	pub header: Header,
+	/// The (unmasked) payload data.
+	pub payload: BytesMut,
+}
+
+impl Frame {
+	/// Create a new frame from the given header and payload data.
+	pub fn new(header: Header, payload: BytesMut) -> Self {
+		Frame { header, payload }
+	}
+}
+
 // Base codec ////////////////////////////////////////////////////////////////////////////////////.
 
 /// If the payload length byte is 126, the following two bytes represent the
@@ -324,13 +361,20 @@ pub struct Codec {
 	max_data_size: usize,
 	/// Bits reserved by an extension.
 	reserved_bits: u8,
+	/// Reserved opcodes used by an extension, one bit per opcode number.
+	reserved_opcodes: u16,
 	/// Scratch buffer used during header encoding.
 	header_buffer: [u8; MAX_HEADER_SIZE],
 }
 
 impl Default for Codec {
 	fn default() -> Self {
-		Codec { max_data_size: 256 * 1024 * 1024, reserved_bits: 0, header_buffer: [0; MAX_HEADER_SIZE] }
+		Codec {
+			max_data_size: 256 * 1024 * 1024,
+			reserved_bits: 0,
+			reserved_opcodes: 0,
+			header_buffer: [0; MAX_HEADER_SIZE],
+		}
 	}
 }
 
@@ -372,6 +416,28 @@ impl Codec {
 		self.reserved_bits = 0
 	}
 
+	/// Is the given reserved opcode in use?
+	pub fn is_reserved_opcode(&self, opcode: OpCode) -> bool {
+		self.reserved_opcodes & (1 << u8::from(opcode)) != 0
+	}
+
+	/// Add to the reserved opcodes in use.
+	///
+	/// Frames with reserved opcodes are rejected when decoding, unless
+	/// the opcode has been added here. Opcodes which are not reserved
+	/// are ignored.
+	pub fn add_reserved_opcodes(&mut self, opcodes: &[OpCode]) -> &mut Self {
+		for opcode in opcodes.iter().filter(|o| o.is_reserved()) {
+			self.reserved_opcodes |= 1 << u8::from(*opcode)
+		}
+		self
+	}
+
+	/// Reset the reserved opcodes.
+	pub fn clear_reserved_opcodes(&mut self) {
+		self.reserved_opcodes = 0
+	}
+
 	/// Decode a websocket frame header.
 	pub fn decode_header(&self, bytes: &[u8]) -> Result<Parsing<Header, usize>, Error> {
 		if bytes.len() < 2 {
@@ -385,11 +451,11 @@ impl Codec {
 		let fin = first & 0x80 != 0;
 		let opcode = OpCode::try_from(first & 0xF)?;
 
-		if opcode.is_reserved() {
+		if opcode.is_reserved() && !self.is_reserved_opcode(opcode) {
 			return Err(Error::ReservedOpCode);
 		}
 
-		if opcode.is_control() && !fin {
+		if (opcode.is_control() || opcode.is_reserved_control()) && !fin {
 			return Err(Error::FragmentedControl);
 		}
 
@@ -436,7 +502,7 @@ impl Codec {
 			n => u64::from(n),
 		};
 
-		if len > MAX_CTRL_BODY_SIZE && header.opcode().is_control() {
+		if len > MAX_CTRL_BODY_SIZE && (header.opcode().is_control() || header.opcode().is_reserved_control()) {
 			return Err(Error::InvalidControlFrameLen);
 		}
 
@@ -496,7 +562,7 @@ impl Codec {
 			second_byte |= len as u8;
 			self.header_buffer[offset] = second_byte;
 			offset += 1;
-		} else if len <= usize::from(u16::max_value()) {
+		} else if len <= usize::from(u16::MAX) {
 			second_byte |= TWO_EXT;
 			self.header_buffer[offset] = second_byte;
 			offset += 1;
@@ -527,13 +593,106 @@ impl Codec {
 			}
 		}
 	}
+
+	/// Decode a complete websocket frame.
+	///
+	/// If the frame is complete, it is removed from `bytes` and `offset` is
+	/// the number of bytes it occupied. The payload data is unmasked. If
+	/// the frame is incomplete, `bytes` is unchanged and the number of
+	/// missing bytes is returned.
+	pub fn decode_frame(&self, bytes: &mut BytesMut) -> Result<Parsing<Frame, usize>, Error> {
+		let (header, offset) = match self.decode_header(bytes)? {
+			Parsing::Done { value, offset } => (value, offset),
+			Parsing::NeedMore(n) => return Ok(Parsing::NeedMore(n)),
+		};
+		let total = offset + header.payload_len();
+		if bytes.len() < total {
+			return Ok(Parsing::NeedMore(total - bytes.len()));
+		}
+		bytes.advance(offset);
+		let mut payload = bytes.split_to(header.payload_len());
+		Codec::apply_mask(&header, &mut payload);
+		Ok(Parsing::Done { value: Frame { header, payload }, offset: total })
+	}
+
+	/// Encode a complete websocket frame and append it to `dest`.
+	///
+	/// The payload length of the header is taken from the payload data,
+	/// which is masked if the header is. As when decoding, reserved bits
+	/// must have been added with [`Codec::add_reserved_bits`] to be set.
+	pub fn encode_frame(&mut self, frame: &Frame, dest: &mut BytesMut) -> Result<(), Error> {
+		if frame.header.is_rsv1() && (self.reserved_bits & 4 == 0) {
+			return Err(Error::InvalidReservedBit(1));
+		}
+		if frame.header.is_rsv2() && (self.reserved_bits & 2 == 0) {
+			return Err(Error::InvalidReservedBit(2));
+		}
+		if frame.header.is_rsv3() && (self.reserved_bits & 1 == 0) {
+			return Err(Error::InvalidReservedBit(3));
+		}
+		let len = frame.payload.len();
+		if len > self.max_data_size {
+			return Err(Error::PayloadTooLarge { actual: as_u64(len), maximum: as_u64(self.max_data_size) });
+		}
+		let mut header = frame.header.clone();
+		header.set_payload_len(len);
+		dest.extend_from_slice(self.encode_header(&header));
+		let start = dest.len();
+		dest.extend_from_slice(&frame.payload);
+		Codec::apply_mask(&header, &mut dest[start..]);
+		Ok(())
+	}
 }
 
+// Handshake keys /////////////////////////////////////////////////////////////////////////////////
+
+// Defined in RFC 6455 and used to generate the `Sec-WebSocket-Accept` header
+// in the server handshake response.
+const KEY: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Owned value of the `Sec-WebSocket-Key` header.
+///
+/// Per [RFC 6455](https://datatracker.ietf.org/doc/html/rfc6455#section-4.1):
+///
+/// ```text
+/// (...) The value of this header field MUST be a
+/// nonce consisting of a randomly selected 16-byte value that has
+/// been base64-encoded (see Section 4 of [RFC4648]). (...)
+/// ```
+///
+/// Base64 encoding of the nonce produces 24 ASCII bytes, padding included.
+pub type WebSocketKey = [u8; 24];
+
+/// Compute the `Sec-WebSocket-Accept` header value for the given `Sec-WebSocket-Key`.
+///
+/// The result is the base64 encoding of a 160 bit SHA-1 hash. Base64 uses one
+/// ASCII character per 6 bits, padded with '=' to a multiple of 4 characters,
+/// hence 28 bytes.
+///
+/// See [RFC 6455, section 1.3](https://datatracker.ietf.org/doc/html/rfc6455#section-1.3)
+/// for more information.
+pub fn generate_accept_key(key_base64: &WebSocketKey) -> [u8; 28] {
+	let mut digest = Sha1::new();
+	digest.update(key_base64);
+	digest.update(KEY);
+	let d = digest.finalize();
+
+	let mut output_buf = [0; 28];
+	let n = base64::engine::general_purpose::STANDARD
+		.encode_slice(d, &mut output_buf)
+		.expect("encoding to base64 is exactly 28 bytes; qed");
+	debug_assert_eq!(n, 28, "encoding to base64 should be exactly 28 bytes");
+	output_buf
+}
+
+// Base codec errors //////////////////////////////////////////////////////////////////////////////
+
 /// Error cases the base frame decoder may encounter.
 #[non_exhaustive]
 #[derive(Debug)]
 pub enum Error {
 	/// An I/O error has been encountered.
+	#[cfg(feature = "std")]
 	Io(io::Error),
 	/// Some unknown opcode number has been decoded.
 	UnknownOpCode,
@@ -552,6 +711,7 @@ pub enum Error {
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
+			#[cfg(feature = "std")]
 			Error::Io(e) => write!(f, "i/o error: {}", e),
 			Error::UnknownOpCode => f.write_str("unknown opcode"),
 			Error::ReservedOpCode => f.write_str("reserved opcode"),
@@ -565,6 +725,7 @@ impl fmt::Display for Error {
 	}
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for Error {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
 		match self {
@@ -579,6 +740,7 @@ impl std::error::Error for Error {
 	}
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for Error {
 	fn from(e: io::Error) -> Self {
 		Error::Io(e)
@@ -595,10 +757,43 @@ impl From<UnknownOpCode> for Error {
 
 #[cfg(test)]
 mod test {
-	use super::{Codec, Error, OpCode};
+	use super::{generate_accept_key, Codec, Error, Frame, Header, OpCode};
 	use crate::Parsing;
+	use bytes::BytesMut;
 	use quickcheck::QuickCheck;
 
+	#[test]
+	fn frame_roundtrip() {
+		let mut codec = Codec::new();
+		let mut header = Header::new(OpCode::Text);
+		header.set_masked(true).set_mask(0xdeadbeef);
+		let frame = Frame::new(header, BytesMut::from(&b"hello"[..]));
+		let mut bytes = BytesMut::new();
+		codec.encode_frame(&frame, &mut bytes).unwrap();
+		assert_eq!(&[0x81, 0x85, 0xde, 0xad, 0xbe, 0xef], &bytes[..6]);
+		assert_eq!(b'h' ^ 0xde, bytes[6]);
+		bytes.extend_from_slice(&[0x89]);
+
+		let mut partial = bytes.split_to(10);
+		assert!(matches!(codec.decode_frame(&mut partial), Ok(Parsing::NeedMore(1))));
+		assert_eq!(10, partial.len());
+		partial.unsplit(bytes);
+		match codec.decode_frame(&mut partial) {
+			Ok(Parsing::Done { value, offset: 11 }) => {
+				assert_eq!(OpCode::Text, value.header.opcode());
+				assert_eq!(b"hello", &value.payload[..])
+			}
+			other => panic!("unexpected result: {:?}", other),
+		}
+		assert_eq!(&[0x89], &partial[..]);
+	}
+
+	#[test]
+	fn accept_key_matches_rfc_example() {
+		// https://datatracker.ietf.org/doc/html/rfc6455#section-1.3
+		assert_eq!(&generate_accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+	}
+
 	#[test]
 	fn decode_partial_header() {
 		let partial_header: &[u8] = &[0x89];
@@ -637,7 +832,7 @@ mod test {
 		if let Ok(Parsing::Done { value, offset }) = Codec::new().decode_header(partial_payload) {
 			assert_eq!(3, value.payload_len() - (partial_payload.len() - offset))
 		} else {
-			assert!(false)
+			panic!()
 		}
 	}
 
@@ -694,6 +889,21 @@ mod test {
 		}
 	}
 
+	#[test]
+	fn decode_added_reserved_opcodes() {
+		let mut codec = Codec::new();
+		codec.add_reserved_opcodes(&[OpCode::Reserved3, OpCode::Reserved11, OpCode::Text]);
+		assert!(codec.is_reserved_opcode(OpCode::Reserved3));
+		assert!(!codec.is_reserved_opcode(OpCode::Text));
+		assert!(matches!(codec.decode_header(&[0x83, 0x00]), Ok(Parsing::Done { .. })));
+		assert!(matches!(codec.decode_header(&[0x03, 0x00]), Ok(Parsing::Done { .. })));
+		assert!(matches!(codec.decode_header(&[0x8b, 0x00]), Ok(Parsing::Done { .. })));
+		assert!(matches!(codec.decode_header(&[0x0b, 0x00]), Err(Error::FragmentedControl)));
+		assert!(matches!(codec.decode_header(&[0x84, 0x00]), Err(Error::ReservedOpCode)));
+		codec.clear_reserved_opcodes();
+		assert!(matches!(codec.decode_header(&[0x83, 0x00]), Err(Error::ReservedOpCode)));
+	}
+
 	#[test]
 	fn decode_ping_no_data() {
 		let ping_no_data: &mut [u8] = &mut [0x89, 0x80, 0x00, 0x00, 0x00, 0x01];
@@ -706,7 +916,7 @@ mod test {
 			assert!(header.opcode() == OpCode::Ping);
 			assert!(header.payload_len() == 0)
 		} else {
-			assert!(false)
+			panic!()
 		}
 	}
 
@@ -0,0 +1,139 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `Decoder` and `Encoder` implementations of [`Codec`] for [`Frame`]s.
+//!
+//! With the `tokio-codec` feature, [`Codec`] implements the traits of
+//! `tokio_util::codec`, with the `asynchronous-codec` feature those of
+//! the `asynchronous_codec` crate.
+
+use super::{Codec, Error, Frame};
+use crate::Parsing;
+use bytes::BytesMut;
+
+/// Maximum number of bytes reserved at once for missing bytes of a frame.
+const RESERVE_SIZE: usize = 8 * 1024;
+
+/// Decode the next complete frame, reserving space for missing bytes.
+///
+/// The payload length declared in a frame header is not reserved up front,
+/// the buffer grows as the payload data arrives instead.
+fn decode(codec: &Codec, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+	match codec.decode_frame(src)? {
+		Parsing::Done { value, .. } => Ok(Some(value)),
+		Parsing::NeedMore(n) => {
+			src.reserve(std::cmp::min(n, RESERVE_SIZE));
+			Ok(None)
+		}
+	}
+}
+
+#[cfg(feature = "tokio-codec")]
+impl tokio_util::codec::Decoder for Codec {
+	type Item = Frame;
+	type Error = Error;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+		decode(self, src)
+	}
+}
+
+#[cfg(feature = "tokio-codec")]
+impl tokio_util::codec::Encoder<Frame> for Codec {
+	type Error = Error;
+
+	fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Error> {
+		self.encode_frame(&frame, dst)
+	}
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl asynchronous_codec::Decoder for Codec {
+	type Item = Frame;
+	type Error = Error;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+		decode(self, src)
+	}
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl asynchronous_codec::Encoder for Codec {
+	type Item<'a> = Frame;
+	type Error = Error;
+
+	fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Error> {
+		self.encode_frame(&frame, dst)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Codec, Frame};
+	use crate::base::{Error, Header, OpCode};
+	use bytes::BytesMut;
+
+	#[cfg(feature = "tokio-codec")]
+	#[test]
+	fn tokio_codec_roundtrip() {
+		use tokio_util::codec::{Decoder, Encoder};
+
+		let mut codec = Codec::new();
+		let mut header = Header::new(OpCode::Binary);
+		header.set_masked(true).set_mask(0x01020304);
+		let mut buf = BytesMut::new();
+		codec.encode(Frame::new(header, BytesMut::from(&b"hello"[..])), &mut buf).unwrap();
+		assert_eq!(2 + 4 + 5, buf.len());
+		assert_ne!(b"hello", &buf[6..]);
+
+		let mut partial = buf.split_to(8);
+		assert!(codec.decode(&mut partial).unwrap().is_none());
+		partial.unsplit(buf);
+		let frame = codec.decode(&mut partial).unwrap().unwrap();
+		assert_eq!(OpCode::Binary, frame.header.opcode());
+		assert_eq!(b"hello", &frame.payload[..]);
+		assert!(partial.is_empty());
+
+		codec.set_max_data_size(4);
+		let frame = Frame::new(Header::new(OpCode::Text), BytesMut::from(&b"hello"[..]));
+		assert!(matches!(codec.encode(frame.clone(), &mut partial), Err(Error::PayloadTooLarge { .. })));
+		codec.set_max_data_size(5);
+		codec.encode(frame, &mut partial).unwrap();
+		codec.set_max_data_size(4);
+		assert!(matches!(codec.decode(&mut partial), Err(Error::PayloadTooLarge { .. })));
+
+		// A large declared payload length does not allocate space for all of it.
+		codec.set_max_data_size(1024 * 1024 * 1024);
+		let mut header = BytesMut::from(&[0x82, 0x7f, 0, 0, 0, 0, 0x40, 0, 0, 0][..]);
+		assert!(codec.decode(&mut header).unwrap().is_none());
+		assert!(header.capacity() < 64 * 1024);
+	}
+
+	#[cfg(feature = "asynchronous-codec")]
+	#[test]
+	fn asynchronous_codec_rejects_reserved_bits() {
+		use asynchronous_codec::{Decoder, Encoder};
+
+		let mut codec = Codec::new();
+		let mut header = Header::new(OpCode::Text);
+		header.set_rsv1(true);
+		let frame = Frame::new(header, BytesMut::from(&b"hi"[..]));
+		let mut buf = BytesMut::new();
+		assert!(matches!(codec.encode(frame.clone(), &mut buf), Err(Error::InvalidReservedBit(1))));
+		assert!(buf.is_empty());
+
+		let mut sender = Codec::new();
+		sender.add_reserved_bits((true, false, false));
+		sender.encode(frame, &mut buf).unwrap();
+		assert!(matches!(codec.decode(&mut buf.clone()), Err(Error::InvalidReservedBit(1))));
+		codec.add_reserved_bits((true, false, false));
+		let frame = codec.decode(&mut buf).unwrap().unwrap();
+		assert!(frame.header.is_rsv1());
+		assert_eq!(b"hi", &frame.payload[..]);
+	}
+}
@@ -8,26 +8,38 @@
 
 //! A persistent websocket connection after the handshake phase, represented
 //! as a [`Sender`] and [`Receiver`] pair.
+//!
+//! The protocol logic itself is implemented by the I/O-free [`Protocol`]
+//! state machine which may also be used directly.
+
+pub mod protocol;
 
 use crate::data::{ByteSlice125, Data, Incoming};
 use crate::{
-	base::{self, Header, OpCode, MAX_HEADER_SIZE},
-	extension::Extension,
-	Parsing, Storage,
+	base,
+	base::OpCode,
+	extension::{Extension, ReservedBitConflict},
+	mask::MaskSource,
 };
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use futures::{
 	io::{ReadHalf, WriteHalf},
-	lock::BiLock,
+	lock::{BiLock, Mutex, MutexGuard},
 	prelude::*,
 };
-use std::{fmt, io, str};
+use std::{
+	collections::VecDeque,
+	fmt, io,
+	pin::Pin,
+	str,
+	sync::Arc,
+	task::{ready, Context, Poll},
+};
 
-/// Accumulated max. size of a complete message.
-const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+pub use protocol::{Event, Protocol};
 
-/// Max. size of a single message frame.
-const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE;
+/// Maximum number of bytes read from the socket at once.
+const BLOCK_SIZE: usize = 8 * 1024;
 
 /// Is the connection used by a client or server?
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -40,11 +52,7 @@ pub enum Mode {
 
 impl Mode {
 	pub fn is_client(self) -> bool {
-		if let Mode::Client = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Mode::Client)
 	}
 
 	pub fn is_server(self) -> bool {
@@ -66,30 +74,113 @@ impl fmt::Display for Id {
 #[derive(Debug)]
 pub struct Sender<T> {
 	id: Id,
-	mode: Mode,
-	codec: base::Codec,
-	writer: BiLock<WriteHalf<T>>,
-	mask_buffer: Vec<u8>,
-	extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
-	has_extensions: bool,
+	shared: BiLock<Shared<T>>,
+	/// Frames passed to `start_send` which have not been encoded yet.
+	queue: VecDeque<Queued>,
+	/// A CLOSE frame has been queued.
+	is_closing: bool,
 }
 
 /// The receiving half of a connection.
 #[derive(Debug)]
 pub struct Receiver<T> {
 	id: Id,
-	mode: Mode,
-	codec: base::Codec,
 	reader: ReadHalf<T>,
-	writer: BiLock<WriteHalf<T>>,
-	extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
-	has_extensions: bool,
-	buffer: BytesMut,
-	ctrl_buffer: BytesMut,
-	max_message_size: usize,
+	shared: BiLock<Shared<T>>,
+	/// Payload data of the last PONG or reserved opcode frame.
+	payload: Bytes,
+	/// An answer to a control frame needs to be written and flushed.
+	flushing: bool,
+	/// A CLOSE frame has been received but not fully processed.
+	closing: Option<Option<CloseReason>>,
 	is_closed: bool,
 }
 
+/// The connection state shared by [`Sender`] and [`Receiver`].
+#[derive(Debug)]
+struct Shared<T> {
+	protocol: Protocol,
+	writer: Writer<T>,
+}
+
+/// The write half of a connection.
+#[derive(Debug)]
+struct Writer<T> {
+	io: WriteHalf<T>,
+	/// Encoded frame data which still needs to be written before anything else.
+	pending: VecDeque<Bytes>,
+	/// A frame header has been written but not all of its payload data.
+	incomplete: bool,
+}
+
+impl<T: AsyncWrite + Unpin> Writer<T> {
+	/// Write all pending data to the socket.
+	///
+	/// Data which could not be written yet stays pending.
+	fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		while let Some(chunk) = self.pending.front_mut() {
+			if chunk.is_empty() {
+				self.pending.pop_front();
+				continue;
+			}
+			let n = ready!(Pin::new(&mut self.io).poll_write(cx, chunk))?;
+			if n == 0 {
+				return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+			}
+			chunk.advance(n)
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<T: AsyncWrite + Unpin> Shared<T> {
+	/// Write all output of the protocol to the socket.
+	///
+	/// Fails if a frame is incomplete, cf. [`Sender::send_binary_mut`].
+	fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		if self.writer.incomplete {
+			return Poll::Ready(Err(Error::Closed));
+		}
+		self.poll_write_output(cx)
+	}
+
+	/// Write all output of the protocol to the socket, even if a frame is incomplete.
+	fn poll_write_output(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		self.protocol.move_output(&mut self.writer.pending);
+		self.writer.poll_write_pending(cx).map_err(|_| Error::Closed)
+	}
+
+	/// Write all output of the protocol to the socket and flush it.
+	fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		ready!(self.poll_write(cx))?;
+		Pin::new(&mut self.writer.io).poll_flush(cx).map_err(|_| Error::Closed)
+	}
+}
+
+/// A frame queued by a [`Sender`].
+#[derive(Debug)]
+enum Queued {
+	/// A frame to which extensions may be applied.
+	Frame(OpCode, Vec<u8>),
+	/// A frame to which no extensions are applied.
+	Uncompressed(OpCode, Vec<u8>),
+	/// A fragment of a message and whether it is the last one.
+	Fragment(OpCode, Vec<u8>, bool),
+	/// A frame which is sent as given.
+	Raw(base::Header, Vec<u8>),
+	/// A CLOSE frame.
+	Close,
+}
+
+/// The outcome of processing incoming frames, cf. [`Incoming`].
+#[derive(Debug)]
+enum Received {
+	Data(Data),
+	Pong,
+	Closed(CloseReason),
+	Reserved(OpCode),
+}
+
 /// A connection builder.
 ///
 /// Allows configuring certain parameters and extensions before
@@ -97,13 +188,8 @@ pub struct Receiver<T> {
 /// connection.
 #[derive(Debug)]
 pub struct Builder<T> {
-	id: Id,
-	mode: Mode,
 	socket: T,
-	codec: base::Codec,
-	extensions: Vec<Box<dyn Extension + Send>>,
-	buffer: BytesMut,
-	max_message_size: usize,
+	protocol: Protocol,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
@@ -116,83 +202,84 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
 	/// [0]: https://tools.ietf.org/html/rfc6455#section-4
 	/// [1]: crate::handshake
 	pub fn new(socket: T, mode: Mode) -> Self {
-		let mut codec = base::Codec::default();
-		codec.set_max_data_size(MAX_FRAME_SIZE);
-		Builder {
-			id: Id(rand::random()),
-			mode,
-			socket,
-			codec,
-			extensions: Vec::new(),
-			buffer: BytesMut::new(),
-			max_message_size: MAX_MESSAGE_SIZE,
-		}
+		Builder { socket, protocol: Protocol::new(mode) }
 	}
 
 	/// Set a custom buffer to use.
 	pub fn set_buffer(&mut self, b: BytesMut) {
-		self.buffer = b
+		self.protocol.set_buffer(b)
+	}
+
+	/// Set the source of frame masks.
+	///
+	/// By default, the thread-local RNG is used. Unless it has been set
+	/// with [`Builder::set_id`], the connection ID is drawn from the new
+	/// source as well.
+	pub fn set_mask_source(&mut self, masks: Box<dyn MaskSource + Send>) {
+		self.protocol.set_mask_source(masks)
+	}
+
+	/// Set the connection ID.
+	///
+	/// The ID is included in log messages to tell connections apart.
+	/// By default, a random ID is used.
+	pub fn set_id(&mut self, id: u32) {
+		self.protocol.set_id(id)
+	}
+
+	/// The `tracing` span of this connection.
+	///
+	/// The span has the fields `id`, `mode`, `peer` and `extensions`.
+	/// The `peer` field is left empty and can be recorded by the caller.
+	#[cfg(feature = "tracing")]
+	pub fn span(&self) -> &tracing::Span {
+		self.protocol.span()
 	}
 
 	/// Add extensions to use with this connection.
 	///
-	/// Only enabled extensions will be considered.
-	pub fn add_extensions<I>(&mut self, extensions: I)
+	/// Only enabled extensions will be considered. Fails and adds none of
+	/// the extensions if two extensions use the same reserved bit.
+	pub fn add_extensions<I>(&mut self, extensions: I) -> Result<(), ReservedBitConflict>
 	where
 		I: IntoIterator<Item = Box<dyn Extension + Send>>,
 	{
-		for e in extensions.into_iter().filter(|e| e.is_enabled()) {
-			log::debug!("{}: using extension: {}", self.id, e.name());
-			self.codec.add_reserved_bits(e.reserved_bits());
-			self.extensions.push(e)
-		}
+		self.protocol.add_extensions(extensions)
 	}
 
 	/// Set the maximum size of a complete message.
 	///
 	/// Message fragments will be buffered and concatenated up to this value,
 	/// i.e. the sum of all message frames payload lengths will not be greater
-	/// than this maximum. However, extensions may increase the total message
-	/// size further, e.g. by decompressing the payload data.
+	/// than this maximum. The maximum is passed on to extensions which
+	/// should not exceed it either, e.g. when decompressing payload data.
 	pub fn set_max_message_size(&mut self, max: usize) {
-		self.max_message_size = max
+		self.protocol.set_max_message_size(max)
 	}
 
 	/// Set the maximum size of a single websocket frame payload.
 	pub fn set_max_frame_size(&mut self, max: usize) {
-		self.codec.set_max_data_size(max);
+		self.protocol.set_max_frame_size(max)
 	}
 
 	/// Create a configured [`Sender`]/[`Receiver`] pair.
 	pub fn finish(self) -> (Sender<T>, Receiver<T>) {
 		let (rhlf, whlf) = self.socket.split();
-		let (wrt1, wrt2) = BiLock::new(whlf);
-		let has_extensions = !self.extensions.is_empty();
-		let (ext1, ext2) = BiLock::new(self.extensions);
+		let id = self.protocol.id();
+		let writer = Writer { io: whlf, pending: VecDeque::new(), incomplete: false };
+		let (sh1, sh2) = BiLock::new(Shared { protocol: self.protocol, writer });
 
 		let recv = Receiver {
-			id: self.id,
-			mode: self.mode,
+			id,
 			reader: rhlf,
-			writer: wrt1,
-			codec: self.codec.clone(),
-			extensions: ext1,
-			has_extensions,
-			buffer: self.buffer,
-			ctrl_buffer: BytesMut::new(),
-			max_message_size: self.max_message_size,
+			shared: sh1,
+			payload: Bytes::new(),
+			flushing: false,
+			closing: None,
 			is_closed: false,
 		};
 
-		let send = Sender {
-			id: self.id,
-			mode: self.mode,
-			writer: wrt2,
-			mask_buffer: Vec::new(),
-			codec: self.codec,
-			extensions: ext2,
-			has_extensions,
-		};
+		let send = Sender { id, shared: sh2, queue: VecDeque::new(), is_closing: false };
 
 		(send, recv)
 	}
@@ -208,411 +295,460 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
 	/// Interleaved PONG frames are returned immediately as `Data::Pong`
 	/// values. If PONGs are not expected or uninteresting,
 	/// [`Receiver::receive_data`] may be used instead which skips over PONGs
-	/// and considers only application payload data.
+	/// and considers only application payload data. The same applies to
+	/// frames with reserved opcodes which are returned as `Incoming::Reserved`
+	/// unless the extension using the opcode consumes them.
+	///
+	/// # Cancel safety
+	///
+	/// This method is cancellation safe. All state of a partially received
+	/// frame or message is kept inside the `Receiver` and `message` is only
+	/// modified once a complete message is available. If the returned future
+	/// is dropped, e.g. in a `select!` branch which did not complete, the next
+	/// call resumes where the previous one stopped.
 	pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
-		let mut first_fragment_opcode = None;
-		let mut length: usize = 0;
-		let message_len = message.len();
-		loop {
-			if self.is_closed {
-				log::debug!("{}: cannot receive, connection is closed", self.id);
-				return Err(Error::Closed);
-			}
-
-			self.ctrl_buffer.clear();
-			let mut header = self.receive_header().await?;
-			log::trace!("{}: recv: {}", self.id, header);
-
-			// Handle control frames: PING, PONG and CLOSE.
-			if header.opcode().is_control() {
-				self.read_buffer(&header).await?;
-				self.ctrl_buffer = self.buffer.split_to(header.payload_len());
-				base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
-				if header.opcode() == OpCode::Pong {
-					return Ok(Incoming::Pong(&self.ctrl_buffer[..]));
-				}
-				if let Some(close_reason) = self.on_control(&header).await? {
-					log::trace!("{}: recv, incoming CLOSE: {:?}", self.id, close_reason);
-					return Ok(Incoming::Closed(close_reason));
-				}
-				continue;
-			}
-
-			length = length.saturating_add(header.payload_len());
-
-			// Check if total message does not exceed maximum.
-			if length > self.max_message_size {
-				log::warn!("{}: accumulated message length exceeds maximum", self.id);
-
-				// Discard bytes that were too large to fit in the buffer.
-				discard_bytes(length as u64, &mut self.reader).await?;
-				return Err(Error::MessageTooLarge { current: length, maximum: self.max_message_size });
-			}
-
-			// Get the frame's payload data bytes from buffer or socket.
-			{
-				let old_msg_len = message.len();
-
-				let bytes_to_read = {
-					let required = header.payload_len();
-					let buffered = self.buffer.len();
-
-					if buffered == 0 {
-						required
-					} else if required > buffered {
-						message.extend_from_slice(&self.buffer);
-						self.buffer.clear();
-						required - buffered
-					} else {
-						message.extend_from_slice(&self.buffer.split_to(required));
-						0
-					}
-				};
-
-				if bytes_to_read > 0 {
-					let n = message.len();
-					message.resize(n + bytes_to_read, 0u8);
-					self.reader.read_exact(&mut message[n..]).await?
-				}
-
-				debug_assert_eq!(header.payload_len(), message.len() - old_msg_len);
-
-				base::Codec::apply_mask(&header, &mut message[old_msg_len..]);
-			}
-
-			match (header.is_fin(), header.opcode()) {
-				(false, OpCode::Continue) => {
-					// Intermediate message fragment.
-					if first_fragment_opcode.is_none() {
-						log::debug!("{}: continue frame while not processing message fragments", self.id);
-						return Err(Error::UnexpectedOpCode(OpCode::Continue));
-					}
-					continue;
-				}
-				(false, oc) => {
-					// Initial message fragment.
-					if first_fragment_opcode.is_some() {
-						log::debug!("{}: initial fragment while processing a fragmented message", self.id);
-						return Err(Error::UnexpectedOpCode(oc));
-					}
-					first_fragment_opcode = Some(oc);
-					self.decode_with_extensions(&mut header, message).await?;
-					continue;
-				}
-				(true, OpCode::Continue) => {
-					// Last message fragment.
-					if let Some(oc) = first_fragment_opcode.take() {
-						header.set_payload_len(message.len());
-						log::trace!("{}: last fragment: total length = {} bytes", self.id, message.len());
-						self.decode_with_extensions(&mut header, message).await?;
-						header.set_opcode(oc);
-					} else {
-						log::debug!("{}: last continue frame while not processing message fragments", self.id);
-						return Err(Error::UnexpectedOpCode(OpCode::Continue));
-					}
-				}
-				(true, oc) => {
-					// Regular non-fragmented message.
-					if first_fragment_opcode.is_some() {
-						log::debug!("{}: regular message while processing fragmented message", self.id);
-						return Err(Error::UnexpectedOpCode(oc));
-					}
-					self.decode_with_extensions(&mut header, message).await?
-				}
-			}
-
-			let num_bytes = message.len() - message_len;
-
-			if header.opcode() == OpCode::Text {
-				return Ok(Incoming::Data(Data::Text(num_bytes)));
-			} else {
-				return Ok(Incoming::Data(Data::Binary(num_bytes)));
-			}
-		}
+		let received = future::poll_fn(|cx| self.poll_received(cx, message)).await?;
+		Ok(self.incoming(received))
 	}
 
 	/// Receive the next websocket message, skipping over control frames.
+	///
+	/// # Cancel safety
+	///
+	/// This method is cancellation safe, cf. [`Receiver::receive`].
 	pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
 		loop {
-			if let Incoming::Data(d) = self.receive(message).await? {
+			if let Received::Data(d) = future::poll_fn(|cx| self.poll_received(cx, message)).await? {
 				return Ok(d);
 			}
 		}
 	}
 
-	/// Read the next frame header.
-	async fn receive_header(&mut self) -> Result<Header, Error> {
+	/// Poll for the next websocket message.
+	///
+	/// This is the poll-based equivalent of [`Receiver::receive`], e.g. for
+	/// use in hand-written `Future` or `Stream` implementations. If
+	/// `Poll::Pending` is returned, the current task is woken up once more
+	/// data may be available and `message` is left unchanged.
+	pub fn poll_receive(&mut self, cx: &mut Context<'_>, message: &mut Vec<u8>) -> Poll<Result<Incoming<'_>, Error>> {
+		let received = ready!(self.poll_received(cx, message))?;
+		Poll::Ready(Ok(self.incoming(received)))
+	}
+
+	/// Receive the next websocket frame as is.
+	///
+	/// This is an advanced API which bypasses the message layer: frames
+	/// are not reassembled, extensions are not applied and PING and CLOSE
+	/// frames are not answered. It must not be used while a message is
+	/// partially received by [`Receiver::receive`].
+	///
+	/// # Cancel safety
+	///
+	/// This method is cancellation safe, cf. [`Receiver::receive`].
+	pub async fn receive_raw_frame(&mut self) -> Result<base::Frame, Error> {
+		future::poll_fn(|cx| self.poll_receive_raw_frame(cx)).await
+	}
+
+	/// Poll for the next websocket frame, cf. [`Receiver::receive_raw_frame`].
+	pub fn poll_receive_raw_frame(&mut self, cx: &mut Context<'_>) -> Poll<Result<base::Frame, Error>> {
+		ready!(self.poll_finish_answer(cx))?;
+		if self.is_closed {
+			log::debug!("{}: cannot receive, connection is closed", self.id);
+			return Poll::Ready(Err(Error::Closed));
+		}
 		loop {
-			match self.codec.decode_header(&self.buffer)? {
-				Parsing::Done { value: header, offset } => {
-					debug_assert!(offset <= MAX_HEADER_SIZE);
-					self.buffer.advance(offset);
-					return Ok(header);
-				}
-				Parsing::NeedMore(n) => crate::read(&mut self.reader, &mut self.buffer, n).await?,
+			let mut shared = ready!(self.shared.poll_lock(cx));
+			let protocol = &mut shared.protocol;
+			if let Some(frame) = protocol.next_raw_frame()? {
+				return Poll::Ready(Ok(frame));
 			}
+			let n = std::cmp::min(protocol.bytes_needed(), BLOCK_SIZE);
+			ready!(crate::poll_read(&mut self.reader, cx, protocol.input_mut(), n))?
 		}
 	}
 
-	/// Read the complete payload data into the read buffer.
-	async fn read_buffer(&mut self, header: &Header) -> Result<(), Error> {
-		if header.payload_len() <= self.buffer.len() {
-			return Ok(());
+	/// Turn the outcome of receiving into the value returned to the caller.
+	fn incoming(&self, received: Received) -> Incoming<'_> {
+		match received {
+			Received::Data(d) => Incoming::Data(d),
+			Received::Pong => Incoming::Pong(&self.payload[..]),
+			Received::Closed(reason) => Incoming::Closed(reason),
+			Received::Reserved(opcode) => Incoming::Reserved(opcode, &self.payload[..]),
 		}
-		let i = self.buffer.len();
-		let d = header.payload_len() - i;
-		self.buffer.resize(i + d, 0u8);
-		self.reader.read_exact(&mut self.buffer[i..]).await?;
-		Ok(())
 	}
 
-	/// Answer incoming control frames.
-	/// `PING`: replied to immediately with a `PONG`
-	/// `PONG`: no action
-	/// `CLOSE`: replied to immediately with a `CLOSE`; returns the [`CloseReason`]
-	/// All other [`OpCode`]s return [`Error::UnexpectedOpCode`]
-	async fn on_control(&mut self, header: &Header) -> Result<Option<CloseReason>, Error> {
-		match header.opcode() {
-			OpCode::Ping => {
-				let mut answer = Header::new(OpCode::Pong);
-				let mut unused = Vec::new();
-				let mut data = Storage::Unique(&mut self.ctrl_buffer);
-				write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut answer, &mut data, &mut unused)
-					.await?;
-				self.flush().await?;
-				Ok(None)
+	/// Read from the socket until a message, PONG or CLOSE is complete.
+	fn poll_received(&mut self, cx: &mut Context<'_>, message: &mut Vec<u8>) -> Poll<Result<Received, Error>> {
+		loop {
+			if let Some(reason) = ready!(self.poll_finish_answer(cx))? {
+				return Poll::Ready(Ok(Received::Closed(reason)));
+			}
+
+			if self.is_closed {
+				log::debug!("{}: cannot receive, connection is closed", self.id);
+				return Poll::Ready(Err(Error::Closed));
 			}
-			OpCode::Pong => Ok(None),
-			OpCode::Close => {
-				log::trace!("{}: Acknowledging CLOSE to sender", self.id);
-				let (mut header, reason) = close_answer(&self.ctrl_buffer)?;
-				// Write back a Close frame
-				let mut unused = Vec::new();
-				if let Some(CloseReason { code, .. }) = reason {
-					let mut data = code.to_be_bytes();
-					let mut data = Storage::Unique(&mut data);
-					let _ = write(
-						self.id,
-						self.mode,
-						&mut self.codec,
-						&mut self.writer,
-						&mut header,
-						&mut data,
-						&mut unused,
-					)
-					.await;
-				} else {
-					let mut data = Storage::Unique(&mut []);
-					let _ = write(
-						self.id,
-						self.mode,
-						&mut self.codec,
-						&mut self.writer,
-						&mut header,
-						&mut data,
-						&mut unused,
-					)
-					.await;
+
+			let mut shared = ready!(self.shared.poll_lock(cx));
+			let protocol = &mut shared.protocol;
+			let event = match protocol.next_event(message) {
+				Ok(event) => event,
+				Err(e) => {
+					// A CLOSE frame may have been queued, e.g. if a message is too large.
+					self.flushing = protocol.has_output();
+					return Poll::Ready(Err(e));
+				}
+			};
+			match event {
+				Some(Event::Data(d)) => return Poll::Ready(Ok(Received::Data(d))),
+				Some(Event::Ping(_)) => self.flushing = true,
+				Some(Event::Pong(data)) => {
+					self.payload = data;
+					return Poll::Ready(Ok(Received::Pong));
+				}
+				Some(Event::Reserved(opcode, data)) => {
+					self.payload = data;
+					return Poll::Ready(Ok(Received::Reserved(opcode)));
+				}
+				Some(Event::Closed(reason)) => {
+					self.flushing = true;
+					self.closing = Some(reason)
+				}
+				None => {
+					let n = std::cmp::min(protocol.bytes_needed(), BLOCK_SIZE);
+					ready!(crate::poll_read(&mut self.reader, cx, protocol.input_mut(), n))?
 				}
-				self.flush().await?;
-				// Close down the connection but the I/O stream could already be closed and
-				// we don't want propagate such error to the user if the I/O was already closed.
-				_ = self.writer.lock().await.close().await;
-				self.is_closed = true;
-				Ok(reason)
 			}
-			OpCode::Binary
-			| OpCode::Text
-			| OpCode::Continue
-			| OpCode::Reserved3
-			| OpCode::Reserved4
-			| OpCode::Reserved5
-			| OpCode::Reserved6
-			| OpCode::Reserved7
-			| OpCode::Reserved11
-			| OpCode::Reserved12
-			| OpCode::Reserved13
-			| OpCode::Reserved14
-			| OpCode::Reserved15 => Err(Error::UnexpectedOpCode(header.opcode())),
 		}
 	}
 
-	/// Apply all extensions to the given header and the internal message buffer.
-	async fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>) -> Result<(), Error> {
-		if !self.has_extensions {
-			return Ok(());
+	/// Finish answering a PING or CLOSE frame received earlier.
+	///
+	/// Returns the reason of a CLOSE frame once the connection is closed.
+	fn poll_finish_answer(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<CloseReason>, Error>> {
+		if self.flushing {
+			let result = ready!(self.poll_write_answer(cx));
+			self.flushing = false;
+			if self.closing.is_none() {
+				result?
+			}
 		}
-		for e in self.extensions.lock().await.iter_mut() {
-			log::trace!("{}: decoding with extension: {}", self.id, e.name());
-			e.decode(header, message).map_err(Error::Extension)?
+
+		if let Some(reason) = &self.closing {
+			// Close down the connection but the I/O stream could already be closed and
+			// we don't want propagate such error to the user if the I/O was already closed.
+			let mut shared = ready!(self.shared.poll_lock(cx));
+			let _ = ready!(Pin::new(&mut shared.writer.io).poll_close(cx));
+			drop(shared);
+			let reason = reason.clone();
+			self.closing = None;
+			self.is_closed = true;
+			return Poll::Ready(Ok(reason));
 		}
-		Ok(())
+
+		Poll::Ready(Ok(None))
 	}
 
-	/// Flush the socket buffer.
-	async fn flush(&mut self) -> Result<(), Error> {
+	/// Write the pending control frame answer to the socket and flush it.
+	fn poll_write_answer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		let mut shared = ready!(self.shared.poll_lock(cx));
 		log::trace!("{}: Receiver flushing connection", self.id);
-		if self.is_closed {
-			return Ok(());
-		}
-		self.writer.lock().await.flush().await.or(Err(Error::Closed))
+		shared.poll_flush(cx)
 	}
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
 	/// Send a text value over the websocket connection.
 	pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
-		let mut header = Header::new(OpCode::Text);
-		self.send_frame(&mut header, &mut Storage::Shared(data.as_ref().as_bytes())).await
+		self.send(OpCode::Text, data.as_ref().as_bytes().to_vec()).await
 	}
 
 	/// Send a text value over the websocket connection.
 	///
 	/// This method performs one copy fewer than [`Sender::send_text`].
 	pub async fn send_text_owned(&mut self, data: String) -> Result<(), Error> {
-		let mut header = Header::new(OpCode::Text);
-		self.send_frame(&mut header, &mut Storage::Owned(data.into_bytes())).await
+		self.send(OpCode::Text, data.into_bytes()).await
 	}
 
 	/// Send some binary data over the websocket connection.
 	pub async fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
-		let mut header = Header::new(OpCode::Binary);
-		self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await
+		self.send(OpCode::Binary, data.as_ref().to_vec()).await
 	}
 
 	/// Send some binary data over the websocket connection.
 	///
 	/// This method performs one copy fewer than [`Sender::send_binary`].
 	/// The `data` buffer may be modified by this method, e.g. if masking is necessary.
+	///
+	/// # Cancel safety
+	///
+	/// Unlike the other methods of `Sender`, this method is not cancellation
+	/// safe. The payload data is written directly from `data`, so if the
+	/// future is dropped before it has been written completely, the frame
+	/// can not be finished and every further attempt to send fails with
+	/// [`Error::Closed`].
 	pub async fn send_binary_mut(&mut self, mut data: impl AsMut<[u8]>) -> Result<(), Error> {
-		let mut header = Header::new(OpCode::Binary);
-		self.send_frame(&mut header, &mut Storage::Unique(data.as_mut())).await
+		future::poll_fn(|cx| self.poll_ready(cx)).await?;
+		let data = data.as_mut();
+		let mut shared = self.shared.lock().await;
+		if shared.protocol.send_in_place(OpCode::Binary, data)? {
+			// The frame header is queued, the masked payload follows directly from `data`.
+			shared.writer.incomplete = true;
+			future::poll_fn(|cx| shared.poll_write_output(cx)).await?;
+			shared.writer.io.write_all(data).await.or(Err(Error::Closed))?;
+			shared.writer.incomplete = false
+		}
+		future::poll_fn(|cx| shared.poll_write(cx)).await
+	}
+
+	/// Send some binary data over the websocket connection without
+	/// applying any extensions.
+	///
+	/// This is useful for payload data which is already compressed, e.g.
+	/// images, where compressing it again would only waste CPU time.
+	pub async fn send_binary_uncompressed(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		future::poll_fn(|cx| self.poll_ready(cx)).await?;
+		self.start_send_uncompressed(OpCode::Binary, data.as_ref().to_vec())?;
+		future::poll_fn(|cx| self.poll_ready(cx)).await
 	}
 
 	/// Ping the remote end.
 	pub async fn send_ping(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
-		let mut header = Header::new(OpCode::Ping);
-		self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+		self.send(OpCode::Ping, data.as_ref().to_vec()).await
 	}
 
 	/// Send an unsolicited Pong to the remote.
 	pub async fn send_pong(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
-		let mut header = Header::new(OpCode::Pong);
-		self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+		self.send(OpCode::Pong, data.as_ref().to_vec()).await
+	}
+
+	/// Send a fragment of a TEXT or BINARY message.
+	///
+	/// This allows sending large messages piece by piece, cf.
+	/// [`Sender::start_send_fragment`].
+	pub async fn send_fragment(&mut self, opcode: OpCode, data: Vec<u8>, fin: bool) -> Result<(), Error> {
+		future::poll_fn(|cx| self.poll_ready(cx)).await?;
+		self.start_send_fragment(opcode, data, fin)?;
+		future::poll_fn(|cx| self.poll_ready(cx)).await
+	}
+
+	/// Send a single websocket frame with the given header.
+	///
+	/// This is an advanced API which bypasses the message layer: no
+	/// extensions are applied and the caller is responsible for sending
+	/// valid fragment sequences, cf. [`Sender::start_send_raw_frame`].
+	/// Frames sent by clients are always masked, frames sent by servers
+	/// never are.
+	pub async fn send_raw_frame(&mut self, header: base::Header, data: Vec<u8>) -> Result<(), Error> {
+		future::poll_fn(|cx| self.poll_ready(cx)).await?;
+		self.start_send_raw_frame(header, data);
+		future::poll_fn(|cx| self.poll_ready(cx)).await
 	}
 
 	/// Flush the socket buffer.
 	pub async fn flush(&mut self) -> Result<(), Error> {
 		log::trace!("{}: Sender flushing connection", self.id);
-		self.writer.lock().await.flush().await.or(Err(Error::Closed))
+		future::poll_fn(|cx| self.poll_flush(cx)).await
 	}
 
 	/// Send a close message and close the connection.
 	pub async fn close(&mut self) -> Result<(), Error> {
-		log::trace!("{}: closing connection", self.id);
-		let mut header = Header::new(OpCode::Close);
-		let code = 1000_u16.to_be_bytes(); // 1000 = normal closure
-		self.write(&mut header, &mut Storage::Shared(&code[..])).await?;
-		self.flush().await?;
-		self.writer.lock().await.close().await.or(Err(Error::Closed))
+		future::poll_fn(|cx| self.poll_close(cx)).await
 	}
 
-	/// Send arbitrary websocket frames.
-	///
-	/// Before sending, extensions will be applied to header and payload data.
-	async fn send_frame(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-		if !self.has_extensions {
-			return self.write(header, data).await;
-		}
+	/// Turn this sender into a [`SharedSender`] which can be cloned and
+	/// used from multiple tasks.
+	pub fn into_shared(self) -> SharedSender<T> {
+		SharedSender::new(self)
+	}
 
-		for e in self.extensions.lock().await.iter_mut() {
-			log::trace!("{}: encoding with extension: {}", self.id, e.name());
-			e.encode(header, data).map_err(Error::Extension)?
+	/// Poll the sender to accept another frame with [`Sender::start_send`].
+	///
+	/// Frames passed to `start_send` earlier are written to the socket
+	/// first, but not flushed. This is the poll-based equivalent of the
+	/// `send_*` methods, e.g. for use in hand-written `Future` or `Sink`
+	/// implementations.
+	pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		let mut shared = ready!(self.shared.poll_lock(cx));
+		while let Some(frame) = self.queue.pop_front() {
+			match frame {
+				Queued::Frame(opcode, data) => shared.protocol.send(opcode, data)?,
+				Queued::Uncompressed(opcode, data) => shared.protocol.send_uncompressed(opcode, data)?,
+				Queued::Fragment(opcode, data, fin) => shared.protocol.send_fragment(opcode, data, fin)?,
+				Queued::Raw(header, data) => shared.protocol.send_raw_frame(header, data)?,
+				Queued::Close => shared.protocol.close(),
+			}
 		}
+		shared.poll_write(cx)
+	}
 
-		self.write(header, data).await
+	/// Begin sending a websocket frame.
+	///
+	/// Only TEXT, BINARY, PING and PONG frames are accepted, and the payload
+	/// of PING and PONG frames must not exceed 125 bytes. Extensions are
+	/// applied to TEXT and BINARY frames. The frame is only queued and
+	/// written by subsequent calls to [`Sender::poll_ready`],
+	/// [`Sender::poll_flush`] or [`Sender::poll_close`], so every call
+	/// should be preceded by a successful `poll_ready`.
+	pub fn start_send(&mut self, opcode: OpCode, data: Vec<u8>) -> Result<(), Error> {
+		protocol::check_frame(opcode, data.len())?;
+		self.queue.push_back(Queued::Frame(opcode, data));
+		Ok(())
 	}
 
-	/// Write final header and payload data to socket.
+	/// Begin sending a websocket frame without applying any extensions.
 	///
-	/// The data will be masked if necessary.
-	/// No extensions will be applied to header and payload data.
-	async fn write(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-		write(self.id, self.mode, &mut self.codec, &mut self.writer, header, data, &mut self.mask_buffer).await
+	/// Like [`Sender::start_send`] but the payload data is sent as given,
+	/// e.g. because it is already compressed.
+	pub fn start_send_uncompressed(&mut self, opcode: OpCode, data: Vec<u8>) -> Result<(), Error> {
+		protocol::check_frame(opcode, data.len())?;
+		self.queue.push_back(Queued::Uncompressed(opcode, data));
+		Ok(())
 	}
-}
 
-/// Write header and payload data to socket.
-async fn write<T: AsyncWrite + Unpin>(
-	id: Id,
-	mode: Mode,
-	codec: &mut base::Codec,
-	writer: &mut BiLock<WriteHalf<T>>,
-	header: &mut Header,
-	data: &mut Storage<'_>,
-	mask_buffer: &mut Vec<u8>,
-) -> Result<(), Error> {
-	if mode.is_client() {
-		header.set_masked(true);
-		header.set_mask(rand::random());
-	}
-	header.set_payload_len(data.as_ref().len());
-
-	log::trace!("{}: send: {}", id, header);
-
-	let header_bytes = codec.encode_header(&header);
-	let mut w = writer.lock().await;
-	w.write_all(&header_bytes).await.or(Err(Error::Closed))?;
-
-	if !header.is_masked() {
-		return w.write_all(data.as_ref()).await.or(Err(Error::Closed));
-	}
-
-	match data {
-		Storage::Shared(slice) => {
-			mask_buffer.clear();
-			mask_buffer.extend_from_slice(slice);
-			base::Codec::apply_mask(header, mask_buffer);
-			w.write_all(mask_buffer).await.or(Err(Error::Closed))
-		}
-		Storage::Unique(slice) => {
-			base::Codec::apply_mask(header, slice);
-			w.write_all(slice).await.or(Err(Error::Closed))
+	/// Begin sending a fragment of a TEXT or BINARY message.
+	///
+	/// All fragments of a message are sent with the same opcode, the last
+	/// one with `fin` set. No other messages may be sent before the last
+	/// fragment, but PING and PONG frames may. Extensions see the fragments
+	/// as individual frames, e.g. to compress the message on the fly.
+	pub fn start_send_fragment(&mut self, opcode: OpCode, data: Vec<u8>, fin: bool) -> Result<(), Error> {
+		if !matches!(opcode, OpCode::Text | OpCode::Binary) {
+			return Err(Error::UnexpectedOpCode(opcode));
 		}
-		Storage::Owned(ref mut bytes) => {
-			base::Codec::apply_mask(header, bytes);
-			w.write_all(bytes).await.or(Err(Error::Closed))
+		self.queue.push_back(Queued::Fragment(opcode, data, fin));
+		Ok(())
+	}
+
+	/// Begin sending a websocket frame with the given header.
+	///
+	/// Like [`Sender::start_send`] but the frame is sent as given, i.e. no
+	/// extensions are applied and any opcode and FIN bit are allowed. RSV
+	/// bits are only allowed if an enabled extension uses them, otherwise
+	/// sending fails. Only the payload length and the mask are set by the
+	/// connection. Sending a CLOSE frame starts the closing handshake.
+	pub fn start_send_raw_frame(&mut self, header: base::Header, data: Vec<u8>) {
+		self.queue.push_back(Queued::Raw(header, data))
+	}
+
+	/// Write all queued frames to the socket and flush it.
+	pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		ready!(self.poll_ready(cx))?;
+		let mut shared = ready!(self.shared.poll_lock(cx));
+		shared.poll_flush(cx)
+	}
+
+	/// Send a close message after all queued frames and close the connection.
+	pub fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+		if !self.is_closing {
+			self.queue.push_back(Queued::Close);
+			self.is_closing = true
 		}
+		ready!(self.poll_flush(cx))?;
+		let mut shared = ready!(self.shared.poll_lock(cx));
+		Pin::new(&mut shared.writer.io).poll_close(cx).map_err(|_| Error::Closed)
+	}
+
+	/// Send a single frame and write it to the socket.
+	async fn send(&mut self, opcode: OpCode, data: Vec<u8>) -> Result<(), Error> {
+		future::poll_fn(|cx| self.poll_ready(cx)).await?;
+		self.start_send(opcode, data)?;
+		future::poll_fn(|cx| self.poll_ready(cx)).await
+	}
+}
+
+/// A cloneable handle to the sending half of a connection.
+///
+/// All clones share the same [`Sender`]. Every method holds exclusive access
+/// to it for the duration of a complete message, so messages sent through
+/// different clones are never interleaved on the wire. Use
+/// [`SharedSender::lock`] to send a sequence of frames (e.g. the fragments
+/// of a message) without any other clone getting in between.
+#[derive(Debug)]
+pub struct SharedSender<T> {
+	inner: Arc<Mutex<Sender<T>>>,
+}
+
+impl<T> Clone for SharedSender<T> {
+	fn clone(&self) -> Self {
+		SharedSender { inner: self.inner.clone() }
+	}
+}
+
+impl<T> From<Sender<T>> for SharedSender<T> {
+	fn from(s: Sender<T>) -> Self {
+		SharedSender::new(s)
+	}
+}
+
+impl<T> SharedSender<T> {
+	/// Create a new shared handle to the given [`Sender`].
+	pub fn new(sender: Sender<T>) -> Self {
+		SharedSender { inner: Arc::new(Mutex::new(sender)) }
+	}
+
+	/// Acquire exclusive access to the underlying [`Sender`].
+	///
+	/// Other clones will wait until the returned guard is dropped.
+	pub async fn lock(&self) -> MutexGuard<'_, Sender<T>> {
+		self.inner.lock().await
 	}
 }
 
-/// Create a close frame based on the given data. The close frame is echoed back
-/// to the sender.
-fn close_answer(data: &[u8]) -> Result<(Header, Option<CloseReason>), Error> {
-	let answer = Header::new(OpCode::Close);
-	if data.len() < 2 {
-		return Ok((answer, None));
-	}
-	// Check that the reason string is properly encoded
-	let descr = std::str::from_utf8(&data[2..])?.into();
-	let code = u16::from_be_bytes([data[0], data[1]]);
-	let reason = CloseReason { code, descr: Some(descr) };
-
-	// Status codes are defined in
-	// https://tools.ietf.org/html/rfc6455#section-7.4.1 and
-	// https://mailarchive.ietf.org/arch/msg/hybi/P_1vbD9uyHl63nbIIbFxKMfSwcM/
-	match code {
-        | 1000 ..= 1003
-        | 1007 ..= 1011
-        | 1012 // Service Restart
-        | 1013 // Try Again Later
-        | 1015
-        | 3000 ..= 4999 => Ok((answer, Some(reason))), // acceptable codes
-        _               => {
-            // invalid code => protocol error (1002)
-            Ok((answer, Some(CloseReason { code: 1002, descr: None})))
-        }
-    }
+impl<T: AsyncRead + AsyncWrite + Unpin> SharedSender<T> {
+	/// Send a text value over the websocket connection.
+	pub async fn send_text(&self, data: impl AsRef<str>) -> Result<(), Error> {
+		self.lock().await.send_text(data).await
+	}
+
+	/// Send a text value over the websocket connection.
+	///
+	/// This method performs one copy fewer than [`SharedSender::send_text`].
+	pub async fn send_text_owned(&self, data: String) -> Result<(), Error> {
+		self.lock().await.send_text_owned(data).await
+	}
+
+	/// Send some binary data over the websocket connection.
+	pub async fn send_binary(&self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		self.lock().await.send_binary(data).await
+	}
+
+	/// Send some binary data over the websocket connection.
+	///
+	/// This method performs one copy fewer than [`SharedSender::send_binary`].
+	/// The `data` buffer may be modified by this method, e.g. if masking is necessary.
+	/// It is not cancellation safe, cf. [`Sender::send_binary_mut`].
+	pub async fn send_binary_mut(&self, data: impl AsMut<[u8]>) -> Result<(), Error> {
+		self.lock().await.send_binary_mut(data).await
+	}
+
+	/// Send some binary data over the websocket connection without
+	/// applying any extensions.
+	pub async fn send_binary_uncompressed(&self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+		self.lock().await.send_binary_uncompressed(data).await
+	}
+
+	/// Ping the remote end.
+	pub async fn send_ping(&self, data: ByteSlice125<'_>) -> Result<(), Error> {
+		self.lock().await.send_ping(data).await
+	}
+
+	/// Send an unsolicited Pong to the remote.
+	pub async fn send_pong(&self, data: ByteSlice125<'_>) -> Result<(), Error> {
+		self.lock().await.send_pong(data).await
+	}
+
+	/// Flush the socket buffer.
+	pub async fn flush(&self) -> Result<(), Error> {
+		self.lock().await.flush().await
+	}
+
+	/// Send a close message and close the connection.
+	///
+	/// Afterwards, sending through any clone of this handle fails.
+	pub async fn close(&self) -> Result<(), Error> {
+		self.lock().await.close().await
+	}
 }
 
 /// Errors which may occur when sending or receiving messages.
@@ -625,6 +761,8 @@ pub enum Error {
 	Codec(base::Error),
 	/// An extension produced an error while encoding or decoding.
 	Extension(crate::BoxedError),
+	/// Two extensions use the same reserved bit.
+	ReservedBitConflict(ReservedBitConflict),
 	/// An unexpected opcode was encountered.
 	UnexpectedOpCode(OpCode),
 	/// A close reason was not correctly UTF-8 encoded.
@@ -648,6 +786,7 @@ impl fmt::Display for Error {
 			Error::Io(e) => write!(f, "i/o error: {}", e),
 			Error::Codec(e) => write!(f, "codec error: {}", e),
 			Error::Extension(e) => write!(f, "extension error: {}", e),
+			Error::ReservedBitConflict(e) => write!(f, "extension conflict: {}", e),
 			Error::UnexpectedOpCode(c) => write!(f, "unexpected opcode: {}", c),
 			Error::Utf8(e) => write!(f, "utf-8 error: {}", e),
 			Error::MessageTooLarge { current, maximum } => {
@@ -664,6 +803,7 @@ impl std::error::Error for Error {
 			Error::Io(e) => Some(e),
 			Error::Codec(e) => Some(e),
 			Error::Extension(e) => Some(&**e),
+			Error::ReservedBitConflict(e) => Some(e),
 			Error::Utf8(e) => Some(e),
 			Error::UnexpectedOpCode(_) | Error::MessageTooLarge { .. } | Error::Closed => None,
 		}
@@ -692,23 +832,205 @@ impl From<base::Error> for Error {
 	}
 }
 
-/// Discard `n` bytes from the underlying reader.
-async fn discard_bytes<R: AsyncRead + Unpin>(n: u64, reader: R) -> Result<u64, io::Error> {
-	futures::io::copy(&mut reader.take(n), &mut futures::io::sink()).await
+impl From<ReservedBitConflict> for Error {
+	fn from(e: ReservedBitConflict) -> Self {
+		Error::ReservedBitConflict(e)
+	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::discard_bytes;
-	use futures::{io::Cursor, AsyncReadExt};
+	use super::{Builder, Error, Mode};
+	use crate::{
+		base::{Header, OpCode},
+		data::{Data, Incoming},
+		mask::Seeded,
+	};
+	use futures::{future, AsyncReadExt};
+	use std::time::Duration;
+	use tokio::io::AsyncWriteExt;
+	use tokio_util::compat::TokioAsyncReadCompatExt;
 
 	#[tokio::test]
-	async fn discard_bytes_works() {
-		let bytes: Vec<u8> = (0..5).collect();
-		let mut cursor = Cursor::new(bytes);
-		discard_bytes(1_u64, &mut cursor).await.unwrap();
-		let mut read = vec![0; 4];
-		cursor.read_exact(&mut read).await.unwrap();
-		assert_eq!(read, vec![1, 2, 3, 4]);
+	async fn too_large_message_is_discarded() {
+		let (a, mut b) = tokio::io::duplex(64);
+		let mut builder = Builder::new(a.compat(), Mode::Client);
+		builder.set_max_message_size(4);
+		let (_, mut receiver) = builder.finish();
+
+		b.write_all(&[0x82, 0x05, 1, 2, 3]).await.unwrap();
+		b.write_all(&[4, 5, 0x81, 0x02, b'h', b'i']).await.unwrap();
+
+		let mut message = Vec::new();
+		assert!(matches!(receiver.receive(&mut message).await, Err(Error::MessageTooLarge { .. })));
+		assert_eq!(Data::Text(2), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"hi", &message[..]);
+	}
+
+	#[tokio::test]
+	async fn receive_resumes_after_cancellation() {
+		let (a, mut b) = tokio::io::duplex(64);
+		let (_, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+		let mut message = Vec::new();
+
+		// A fragmented message whose last fragment is only partially available.
+		b.write_all(&[0x02, 0x02, 1, 2, 0x80, 0x03, 3]).await.unwrap();
+		let pending = tokio::time::timeout(Duration::from_millis(10), receiver.receive(&mut message)).await;
+		assert!(pending.is_err());
+		assert!(message.is_empty());
+
+		// A PING in between whose answer is not affected either.
+		b.write_all(&[4, 5, 0x89, 0x01, 9]).await.unwrap();
+		assert_eq!(Data::Binary(5), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(&[1, 2, 3, 4, 5], &message[..]);
+
+		b.write_all(&[0x81]).await.unwrap();
+		let pending = tokio::time::timeout(Duration::from_millis(10), receiver.receive(&mut message)).await;
+		assert!(pending.is_err());
+		// A masked PONG with the PING's payload.
+		let mut pong = [0; 7];
+		tokio::io::AsyncReadExt::read_exact(&mut b, &mut pong).await.unwrap();
+		assert_eq!([0x8a, 0x81], pong[..2]);
+		assert_eq!(9, pong[6] ^ pong[2]);
+
+		b.write_all(&[0x02, b'o', b'k']).await.unwrap();
+		message.clear();
+		assert_eq!(Data::Text(2), receiver.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"ok", &message[..]);
+	}
+
+	#[tokio::test]
+	async fn poll_api_sends_and_receives() {
+		let (a, b) = tokio::io::duplex(64);
+		let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+		let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+		future::poll_fn(|cx| sender.poll_ready(cx)).await.unwrap();
+		sender.start_send(OpCode::Ping, vec![1]).unwrap();
+		sender.start_send(OpCode::Text, b"hi".to_vec()).unwrap();
+		assert!(matches!(sender.start_send(OpCode::Close, Vec::new()), Err(Error::UnexpectedOpCode(OpCode::Close))));
+		assert!(matches!(sender.start_send(OpCode::Pong, vec![0; 126]), Err(Error::MessageTooLarge { .. })));
+		future::poll_fn(|cx| sender.poll_flush(cx)).await.unwrap();
+
+		let mut message = Vec::new();
+		let is_text = future::poll_fn(|cx| receiver.poll_receive(cx, &mut message).map_ok(|i| i.is_text()));
+		assert!(is_text.await.unwrap());
+		assert_eq!(b"hi", &message[..]);
+
+		future::poll_fn(|cx| sender.poll_close(cx)).await.unwrap();
+		let is_closed =
+			future::poll_fn(|cx| receiver.poll_receive(cx, &mut message).map_ok(|i| matches!(i, Incoming::Closed(_))));
+		assert!(is_closed.await.unwrap());
+	}
+
+	#[tokio::test]
+	async fn shared_sender_does_not_interleave_messages() {
+		let (a, b) = tokio::io::duplex(64);
+		let (sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+		let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+		let sender = sender.into_shared();
+		let tasks: Vec<_> = (0..4u8)
+			.map(|i| {
+				let s = sender.clone();
+				tokio::spawn(async move {
+					for _ in 0..8 {
+						s.send_binary(vec![i; 1000]).await.unwrap();
+						s.flush().await.unwrap();
+					}
+				})
+			})
+			.collect();
+
+		for _ in 0..32 {
+			let mut message = Vec::new();
+			assert_eq!(Data::Binary(1000), receiver.receive_data(&mut message).await.unwrap());
+			assert!(message.iter().all(|b| *b == message[0]));
+		}
+
+		for t in tasks {
+			t.await.unwrap()
+		}
+
+		let other = sender.clone();
+		sender.close().await.unwrap();
+		assert!(matches!(other.send_text("too late").await, Err(Error::Closed)));
+	}
+
+	#[tokio::test]
+	async fn seeded_masks_produce_exact_bytes() {
+		let (a, b) = tokio::io::duplex(64);
+		let mut builder = Builder::new(a.compat(), Mode::Client);
+		builder.set_mask_source(Box::new(Seeded::new(7)));
+		let (mut sender, _) = builder.finish();
+		sender.send_text("hello").await.unwrap();
+		sender.flush().await.unwrap();
+
+		let mut bytes = [0; 11];
+		b.compat().read_exact(&mut bytes).await.unwrap();
+		// FIN + TEXT, MASK + len 5, masking key, masked "hello"
+		assert_eq!(bytes, [0x81, 0x85, 0x04, 0x4c, 0x3c, 0xd7, 0x6c, 0x29, 0x50, 0xbb, 0x6b]);
+	}
+
+	#[tokio::test]
+	async fn send_binary_mut_masks_in_place() {
+		let (a, b) = tokio::io::duplex(1024);
+		let mut builder = Builder::new(a.compat(), Mode::Client);
+		builder.set_mask_source(Box::new(Seeded::new(7)));
+		let (mut client, _) = builder.finish();
+		let (_, mut server) = Builder::new(b.compat(), Mode::Server).finish();
+
+		let mut data = b"hello".to_vec();
+		client.send_binary_mut(&mut data).await.unwrap();
+		let masked = data.clone();
+		assert_ne!(b"hello", &masked[..]);
+		client.send_binary_mut(&mut data).await.unwrap();
+		client.flush().await.unwrap();
+
+		let mut message = Vec::new();
+		assert_eq!(Data::Binary(5), server.receive_data(&mut message).await.unwrap());
+		assert_eq!(b"hello", &message[..]);
+		message.clear();
+		assert_eq!(Data::Binary(5), server.receive_data(&mut message).await.unwrap());
+		assert_eq!(masked, message);
+	}
+
+	#[tokio::test]
+	async fn interrupted_send_binary_mut_closes_sender() {
+		let (a, _b) = tokio::io::duplex(64);
+		let (mut client, _) = Builder::new(a.compat(), Mode::Client).finish();
+
+		// The payload data does not fit into the socket buffer.
+		let mut data = vec![0; 1000];
+		let pending = tokio::time::timeout(Duration::from_millis(10), client.send_binary_mut(&mut data)).await;
+		assert!(pending.is_err());
+		assert!(matches!(client.send_text("hello").await, Err(Error::Closed)));
+	}
+
+	#[tokio::test]
+	async fn raw_frames_are_not_reassembled() {
+		let (a, b) = tokio::io::duplex(1024);
+		let (mut client, _) = Builder::new(a.compat(), Mode::Client).finish();
+		let (_, mut server) = Builder::new(b.compat(), Mode::Server).finish();
+
+		let mut header = Header::new(OpCode::Binary);
+		header.set_fin(false).set_masked(false);
+		client.send_raw_frame(header, b"hel".to_vec()).await.unwrap();
+		client.send_raw_frame(Header::new(OpCode::Continue), b"lo".to_vec()).await.unwrap();
+		client.send_raw_frame(Header::new(OpCode::Ping), b"ping".to_vec()).await.unwrap();
+		client.flush().await.unwrap();
+
+		let frame = server.receive_raw_frame().await.unwrap();
+		assert_eq!(OpCode::Binary, frame.header.opcode());
+		assert!(!frame.header.is_fin());
+		assert!(frame.header.is_masked());
+		assert_eq!(b"hel", &frame.payload[..]);
+		let frame = server.receive_raw_frame().await.unwrap();
+		assert_eq!(OpCode::Continue, frame.header.opcode());
+		assert!(frame.header.is_fin());
+		assert_eq!(b"lo", &frame.payload[..]);
+		let frame = server.receive_raw_frame().await.unwrap();
+		assert_eq!(OpCode::Ping, frame.header.opcode());
+		assert_eq!(b"ping", &frame.payload[..]);
 	}
 }
@@ -0,0 +1,1334 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A websocket connection as a state machine without any I/O.
+//!
+//! Bytes received from the remote end are added to the input buffer of a
+//! [`Protocol`] and turned into [`Event`]s by [`Protocol::next_event`].
+//! Frames to send, including answers to PING and CLOSE frames, are encoded
+//! into an output queue which needs to be written to the remote end by the
+//! caller. This allows using websocket connections with any kind of I/O,
+//! e.g. custom event loops or deterministic simulations.
+//!
+//! [`Sender`](super::Sender) and [`Receiver`](super::Receiver) are built on
+//! top of this state machine.
+
+use super::{CloseReason, Error, Id, Mode};
+use crate::{
+	base::{self, Frame, Header, OpCode, MAX_HEADER_SIZE},
+	data::Data,
+	extension::{self, Extension, Fragment, FrameContext, ReservedBitConflict},
+	mask::{MaskSource, ThreadRng},
+	BoxedError, Parsing, Storage,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::VecDeque;
+
+/// Accumulated max. size of a complete message.
+const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Max. size of a single message frame.
+const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE;
+
+/// Max. number of bytes requested at once when skipping over payload data.
+const DISCARD_BLOCK_SIZE: usize = 8 * 1024;
+
+/// Max. payload size of a control frame.
+const MAX_CONTROL_PAYLOAD_SIZE: usize = 125;
+
+/// Something that happened on a connection, cf. [`Protocol::next_event`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+	/// A complete text or binary message has been received.
+	Data(Data),
+	/// A PING has been received and answered with a PONG.
+	Ping(Bytes),
+	/// A PONG has been received.
+	Pong(Bytes),
+	/// A CLOSE has been received and answered with a CLOSE, unless a CLOSE
+	/// has been sent before.
+	///
+	/// The reason is `None` if the remote end did not provide one.
+	Closed(Option<CloseReason>),
+	/// A frame with a reserved opcode has been received which the extension
+	/// using the opcode did not consume.
+	///
+	/// Fragmented data frames are reassembled.
+	Reserved(OpCode, Bytes),
+}
+
+/// The websocket protocol state of a connection.
+#[derive(Debug)]
+pub struct Protocol {
+	id: Id,
+	/// The ID has been set with [`Protocol::set_id`].
+	explicit_id: bool,
+	mode: Mode,
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
+	codec: base::Codec,
+	masks: Box<dyn MaskSource + Send>,
+	extensions: Vec<Box<dyn Extension + Send>>,
+	/// Received bytes which have not been processed yet.
+	input: BytesMut,
+	/// Encoded frames which need to be sent to the remote end.
+	output: VecDeque<Bytes>,
+	/// Header of the frame whose payload is currently being received.
+	header: Option<Header>,
+	/// Payload data of the message currently being received.
+	message: Vec<u8>,
+	/// Header of the initial frame of a fragmented message.
+	fragment: Option<Header>,
+	/// Payload data of a continuation frame which extensions decode on its own.
+	frame: Vec<u8>,
+	/// The payload data of the current frame is received into `frame`
+	/// instead of being appended to `message` directly.
+	separate_frame: bool,
+	/// Offset of the current frame's payload data in its buffer.
+	frame_offset: usize,
+	/// Number of bytes in the current frame's buffer which have been received so far.
+	filled: usize,
+	/// Number of payload bytes to skip.
+	discard: usize,
+	/// Min. number of input bytes needed to make progress.
+	needed: usize,
+	max_message_size: usize,
+	is_closed: bool,
+	/// A CLOSE frame has been added to the output queue.
+	close_sent: bool,
+	/// Opcode of the fragmented message currently being sent.
+	sending: Option<OpCode>,
+}
+
+impl Protocol {
+	/// Create a new protocol state machine for the given mode.
+	///
+	/// **Note**: Use this type only after a successful [handshake][0].
+	///
+	/// [0]: https://tools.ietf.org/html/rfc6455#section-4
+	pub fn new(mode: Mode) -> Self {
+		let mut codec = base::Codec::default();
+		codec.set_max_data_size(MAX_FRAME_SIZE);
+		let mut masks: Box<dyn MaskSource + Send> = Box::new(ThreadRng::new());
+		let id = Id(masks.next_u32());
+		Protocol {
+			id,
+			explicit_id: false,
+			mode,
+			#[cfg(feature = "tracing")]
+			span: tracing::debug_span!(
+				"websocket",
+				id = %id,
+				mode = ?mode,
+				peer = tracing::field::Empty,
+				extensions = tracing::field::Empty
+			),
+			codec,
+			masks,
+			extensions: Vec::new(),
+			input: BytesMut::new(),
+			output: VecDeque::new(),
+			header: None,
+			message: Vec::new(),
+			fragment: None,
+			frame: Vec::new(),
+			separate_frame: false,
+			frame_offset: 0,
+			filled: 0,
+			discard: 0,
+			needed: 1,
+			max_message_size: MAX_MESSAGE_SIZE,
+			is_closed: false,
+			close_sent: false,
+			sending: None,
+		}
+	}
+
+	/// Set a custom input buffer to use.
+	pub fn set_buffer(&mut self, b: BytesMut) {
+		self.input = b
+	}
+
+	/// Set the source of frame masks.
+	///
+	/// By default, the thread-local RNG is used. Unless it has been set
+	/// with [`Protocol::set_id`], the connection ID is drawn from the new
+	/// source as well.
+	pub fn set_mask_source(&mut self, mut masks: Box<dyn MaskSource + Send>) {
+		// Always draw the ID, so the masks do not depend on whether one has been set.
+		let id = masks.next_u32();
+		if !self.explicit_id {
+			self.record_id(id)
+		}
+		self.masks = masks
+	}
+
+	/// Set the connection ID.
+	///
+	/// The ID is included in log messages to tell connections apart.
+	/// By default, a random ID is used.
+	pub fn set_id(&mut self, id: u32) {
+		self.explicit_id = true;
+		self.record_id(id)
+	}
+
+	/// Update the connection ID and the `tracing` span.
+	fn record_id(&mut self, id: u32) {
+		self.id = Id(id);
+		#[cfg(feature = "tracing")]
+		self.span.record("id", tracing::field::display(self.id));
+	}
+
+	/// The `tracing` span of this connection.
+	///
+	/// The span has the fields `id`, `mode`, `peer` and `extensions`.
+	/// The `peer` field is left empty and can be recorded by the caller.
+	#[cfg(feature = "tracing")]
+	pub fn span(&self) -> &tracing::Span {
+		&self.span
+	}
+
+	/// Add extensions to use with this connection.
+	///
+	/// Only enabled extensions will be considered. Fails and adds none of
+	/// the extensions if two extensions use the same reserved bit.
+	pub fn add_extensions<I>(&mut self, extensions: I) -> Result<(), ReservedBitConflict>
+	where
+		I: IntoIterator<Item = Box<dyn Extension + Send>>,
+	{
+		let extensions = extensions.into_iter().filter(|e| e.is_enabled()).collect::<Vec<_>>();
+		extension::check_reserved_bits(self.extensions.iter().chain(&extensions))?;
+		for mut e in extensions {
+			log::debug!("{}: using extension: {}", self.id, e.name());
+			e.set_max_message_size(self.max_message_size);
+			self.codec.add_reserved_bits(e.reserved_bits());
+			self.codec.add_reserved_opcodes(e.reserved_opcodes());
+			self.extensions.push(e)
+		}
+		#[cfg(feature = "tracing")]
+		if !self.extensions.is_empty() {
+			let names = self.extensions.iter().map(|e| e.name()).collect::<Vec<_>>().join(", ");
+			self.span.record("extensions", names.as_str());
+		}
+		Ok(())
+	}
+
+	/// Set the maximum size of a complete message.
+	///
+	/// Message fragments will be buffered and concatenated up to this value,
+	/// i.e. the sum of all message frames payload lengths will not be greater
+	/// than this maximum. The maximum is passed on to extensions which
+	/// should not exceed it either, e.g. when decompressing payload data.
+	pub fn set_max_message_size(&mut self, max: usize) {
+		self.max_message_size = max;
+		for e in self.extensions.iter_mut() {
+			e.set_max_message_size(max)
+		}
+	}
+
+	/// Set the maximum size of a single websocket frame payload.
+	pub fn set_max_frame_size(&mut self, max: usize) {
+		self.codec.set_max_data_size(max);
+	}
+
+	/// Has a CLOSE frame been received?
+	pub fn is_closed(&self) -> bool {
+		self.is_closed
+	}
+
+	/// Add bytes received from the remote end.
+	pub fn receive_bytes(&mut self, bytes: &[u8]) {
+		self.input.extend_from_slice(bytes)
+	}
+
+	/// The buffer of received bytes which have not been processed yet.
+	///
+	/// Instead of using [`Protocol::receive_bytes`], received bytes may be
+	/// appended to this buffer directly.
+	pub fn input_mut(&mut self) -> &mut BytesMut {
+		&mut self.input
+	}
+
+	/// The minimum number of bytes which need to be received before
+	/// [`Protocol::next_event`] can make progress after it returned `None`.
+	pub fn bytes_needed(&self) -> usize {
+		self.needed
+	}
+
+	/// Process the received bytes until the next event occurs.
+	///
+	/// Returns `Ok(None)` if more bytes need to be received first. Once a
+	/// complete message has been received, its payload data is appended to
+	/// `message` and [`Event::Data`] describes its type. Answers to PING and
+	/// CLOSE frames are added to the output queue.
+	pub fn next_event(&mut self, message: &mut Vec<u8>) -> Result<Option<Event>, Error> {
+		loop {
+			if self.is_closed {
+				log::debug!("{}: cannot receive, connection is closed", self.id);
+				return Err(Error::Closed);
+			}
+
+			if !self.discard_payload() {
+				return Ok(None);
+			}
+
+			let mut header = if let Some(h) = &self.header {
+				h.clone()
+			} else {
+				let header = match self.codec.decode_header(&self.input)? {
+					Parsing::Done { value: header, offset } => {
+						debug_assert!(offset <= MAX_HEADER_SIZE);
+						self.input.advance(offset);
+						header
+					}
+					Parsing::NeedMore(n) => {
+						self.needed = n;
+						return Ok(None);
+					}
+				};
+				log::trace!("{}: recv: {}", self.id, header);
+				#[cfg(feature = "tracing")]
+				trace_frame(&self.span, "frame received", &header);
+				if let Err(e) = self.start_frame(&header) {
+					if let Error::MessageTooLarge { .. } = e {
+						// Discard bytes that were too large to fit in the buffer.
+						self.discard = header.payload_len();
+					}
+					return Err(e);
+				}
+				self.header = Some(header.clone());
+				header
+			};
+
+			// Handle control frames: PING, PONG, CLOSE and reserved control opcodes.
+			if header.opcode().is_control() || header.opcode().is_reserved_control() {
+				if self.input.len() < header.payload_len() {
+					self.needed = header.payload_len() - self.input.len();
+					return Ok(None);
+				}
+				self.header = None;
+				let mut data = self.input.split_to(header.payload_len());
+				base::Codec::apply_mask(&header, &mut data);
+				if let Some(event) = self.on_control(&header, data.freeze())? {
+					return Ok(Some(event));
+				}
+				continue;
+			}
+
+			// Take whatever payload data is available.
+			let buffer = if self.separate_frame { &mut self.frame } else { &mut self.message };
+			let n = std::cmp::min(buffer.len() - self.filled, self.input.len());
+			buffer[self.filled..self.filled + n].copy_from_slice(&self.input[..n]);
+			self.input.advance(n);
+			self.filled += n;
+			if self.filled < buffer.len() {
+				self.needed = buffer.len() - self.filled;
+				return Ok(None);
+			}
+
+			self.header = None;
+			base::Codec::apply_mask(&header, &mut buffer[self.frame_offset..]);
+
+			let fragment = match (header.is_fin(), &self.fragment) {
+				(true, None) => Fragment::Unfragmented,
+				(false, None) => Fragment::First,
+				(false, Some(_)) => Fragment::Continuation,
+				(true, Some(_)) => Fragment::Final,
+			};
+			let opcode = self.fragment.as_ref().map_or(header.opcode(), Header::opcode);
+
+			if let Err(e) = self.decode_frame(&mut header, FrameContext::new(fragment, opcode)) {
+				return Err(self.on_decode_error(e));
+			}
+
+			if !header.is_fin() {
+				if fragment == Fragment::First {
+					self.fragment = Some(header)
+				}
+				continue;
+			}
+
+			// The message is complete, continue with the header of its initial frame.
+			let mut header = self.fragment.take().unwrap_or(header);
+			header.set_fin(true);
+			header.set_payload_len(self.message.len());
+			if fragment == Fragment::Final {
+				log::trace!("{}: last fragment: total length = {} bytes", self.id, self.message.len());
+			}
+
+			if let Err(e) = decode_with_extensions(self.id, &mut self.extensions, &mut header, &mut self.message) {
+				return Err(self.on_decode_error(e));
+			}
+
+			if header.opcode().is_reserved() {
+				let data = Bytes::from(std::mem::take(&mut self.message));
+				self.reset_message();
+				if let Some(event) = self.on_reserved(&header, data)? {
+					return Ok(Some(event));
+				}
+				continue;
+			}
+
+			let num_bytes = self.message.len();
+
+			if message.is_empty() {
+				std::mem::swap(message, &mut self.message)
+			} else {
+				message.extend_from_slice(&self.message)
+			}
+			self.reset_message();
+
+			if header.opcode() == OpCode::Text {
+				return Ok(Some(Event::Data(Data::Text(num_bytes))));
+			} else {
+				return Ok(Some(Event::Data(Data::Binary(num_bytes))));
+			}
+		}
+	}
+
+	/// Take the next complete frame from the received bytes.
+	///
+	/// Unlike [`Protocol::next_event`], this does not reassemble messages,
+	/// apply extensions or answer control frames. The payload data is
+	/// unmasked. Returns `Ok(None)` if more bytes need to be received first.
+	///
+	/// Fails with [`Error::UnexpectedOpCode`] if `next_event` has stopped in
+	/// the middle of a frame.
+	pub fn next_raw_frame(&mut self) -> Result<Option<Frame>, Error> {
+		if let Some(h) = &self.header {
+			log::debug!("{}: raw frame requested while receiving a {} frame", self.id, h.opcode());
+			return Err(Error::UnexpectedOpCode(h.opcode()));
+		}
+		if !self.discard_payload() {
+			return Ok(None);
+		}
+		match self.codec.decode_frame(&mut self.input)? {
+			Parsing::Done { value: frame, .. } => {
+				log::trace!("{}: recv raw: {}", self.id, frame.header);
+				#[cfg(feature = "tracing")]
+				trace_frame(&self.span, "frame received", &frame.header);
+				Ok(Some(frame))
+			}
+			Parsing::NeedMore(n) => {
+				self.needed = n;
+				Ok(None)
+			}
+		}
+	}
+
+	/// Add a frame with the given opcode and payload data to the output queue.
+	///
+	/// Only TEXT, BINARY, PING and PONG frames are accepted, and the payload
+	/// of PING and PONG frames must not exceed 125 bytes. Extensions are
+	/// applied to TEXT and BINARY frames.
+	pub fn send(&mut self, opcode: OpCode, data: Vec<u8>) -> Result<(), Error> {
+		self.send_message(opcode, data, true)
+	}
+
+	/// Add a frame with the given opcode and payload data to the output queue
+	/// without applying any extensions.
+	///
+	/// This is useful for payload data which is already compressed. The
+	/// same frames as with [`Protocol::send`] are accepted.
+	pub fn send_uncompressed(&mut self, opcode: OpCode, data: Vec<u8>) -> Result<(), Error> {
+		self.send_message(opcode, data, false)
+	}
+
+	/// Add a fragment of a TEXT or BINARY message to the output queue.
+	///
+	/// All fragments of a message are sent with the same opcode, the last
+	/// one with `fin` set. Control frames may be sent in between, but no
+	/// other messages. Since the message is never complete, only the frame
+	/// hooks of extensions are applied, cf. [`Extension::encode_frame`],
+	/// and the message is refused if an enabled extension can not encode
+	/// it this way, cf. [`Extension::encodes_fragments`]. If the first
+	/// fragment is also the last one, the message is sent as with
+	/// [`Protocol::send`].
+	pub fn send_fragment(&mut self, opcode: OpCode, data: Vec<u8>, fin: bool) -> Result<(), Error> {
+		if !matches!(opcode, OpCode::Text | OpCode::Binary) || self.sending.is_some_and(|o| o != opcode) {
+			return Err(Error::UnexpectedOpCode(opcode));
+		}
+		let fragment = match (self.sending, fin) {
+			(None, true) => return self.send(opcode, data),
+			(None, false) => Fragment::First,
+			(Some(_), false) => Fragment::Continuation,
+			(Some(_), true) => Fragment::Final,
+		};
+		if fragment == Fragment::First {
+			if let Some(e) = self.extensions.iter().find(|e| !e.encodes_fragments()) {
+				let e = format!("extension {} can not encode fragmented messages", e.name());
+				return Err(Error::Extension(e.into()));
+			}
+		}
+		let mut header = Header::new(if fragment == Fragment::First { opcode } else { OpCode::Continue });
+		header.set_fin(fin);
+		let mut data = Storage::Owned(data);
+		let frame = FrameContext::new(fragment, opcode);
+		for e in self.extensions.iter_mut() {
+			log::trace!("{}: encoding frame with extension: {}", self.id, e.name());
+			let before = reserved_bits(&header);
+			e.encode_frame(&mut header, &mut data, frame).map_err(Error::Extension)?;
+			check_reserved_bits(self.id, &**e, before, &header)?
+		}
+		self.sending = if fin { None } else { Some(opcode) };
+		let data = match data {
+			Storage::Owned(bytes) => bytes,
+			other => other.as_ref().to_vec(),
+		};
+		self.encode(header, data);
+		Ok(())
+	}
+
+	/// Encode a single frame message, applying extensions if requested.
+	fn send_message(&mut self, opcode: OpCode, data: Vec<u8>, extensions: bool) -> Result<(), Error> {
+		let mut data = Storage::Owned(data);
+		let header = self.prepare_message(opcode, &mut data, extensions)?;
+		let data = match data {
+			Storage::Owned(bytes) => bytes,
+			other => other.as_ref().to_vec(),
+		};
+		self.encode(header, data);
+		Ok(())
+	}
+
+	/// Encode a single frame message whose payload data is masked in place.
+	///
+	/// Returns `true` if only the frame header has been added to the output
+	/// queue and `data` has to be sent right after it. If extensions have
+	/// replaced the payload data, the complete frame is queued and `false`
+	/// is returned.
+	pub(super) fn send_in_place(&mut self, opcode: OpCode, data: &mut [u8]) -> Result<bool, Error> {
+		let mut storage = Storage::Unique(data);
+		let header = self.prepare_message(opcode, &mut storage, true)?;
+		match storage {
+			Storage::Unique(data) => {
+				let header = self.encode_header(header, data.len());
+				base::Codec::apply_mask(&header, data);
+				Ok(true)
+			}
+			other => {
+				self.encode(header, other.as_ref().to_vec());
+				Ok(false)
+			}
+		}
+	}
+
+	/// Check a single frame message and apply extensions if requested.
+	fn prepare_message(&mut self, opcode: OpCode, data: &mut Storage, extensions: bool) -> Result<Header, Error> {
+		check_frame(opcode, data.as_ref().len())?;
+		if !opcode.is_control() && self.sending.is_some() {
+			// A fragmented message must be completed first.
+			return Err(Error::UnexpectedOpCode(opcode));
+		}
+		let mut header = Header::new(opcode);
+		if extensions && !opcode.is_control() {
+			for e in self.extensions.iter_mut() {
+				log::trace!("{}: encoding with extension: {}", self.id, e.name());
+				let before = reserved_bits(&header);
+				e.encode(&mut header, data).map_err(Error::Extension)?;
+				check_reserved_bits(self.id, &**e, before, &header)?
+			}
+			let frame = FrameContext::new(Fragment::Unfragmented, opcode);
+			for e in self.extensions.iter_mut() {
+				log::trace!("{}: encoding frame with extension: {}", self.id, e.name());
+				let before = reserved_bits(&header);
+				e.encode_frame(&mut header, data, frame).map_err(Error::Extension)?;
+				check_reserved_bits(self.id, &**e, before, &header)?
+			}
+		}
+		Ok(header)
+	}
+
+	/// Add a frame with the given header and payload data to the output queue.
+	///
+	/// No extensions are applied and the header is used as given, except
+	/// for the payload length and masking: frames sent by clients are
+	/// always masked, frames sent by servers never are. Reserved bits may
+	/// only be set if an enabled extension uses them. Sending a CLOSE frame
+	/// starts the closing handshake as with [`Protocol::close`].
+	pub fn send_raw_frame(&mut self, mut header: Header, data: Vec<u8>) -> Result<(), Error> {
+		if let Some(bit) = undeclared_bit(&header, 0, self.codec.reserved_bits()) {
+			log::debug!("{}: raw frame with unused reserved bit: {}", self.id, bit);
+			return Err(Error::Codec(base::Error::InvalidReservedBit(bit)));
+		}
+		if header.opcode() == OpCode::Close {
+			self.close_sent = true
+		}
+		header.set_masked(false);
+		self.encode(header, data);
+		Ok(())
+	}
+
+	/// Add a CLOSE frame with status code 1000 (normal closure) to the output queue.
+	pub fn close(&mut self) {
+		self.send_close(1000) // 1000 = normal closure
+	}
+
+	/// Are there encoded frames which need to be sent?
+	pub fn has_output(&self) -> bool {
+		!self.output.is_empty()
+	}
+
+	/// Take the next chunk of encoded frame data to send to the remote end.
+	pub fn take_output(&mut self) -> Option<Bytes> {
+		self.output.pop_front()
+	}
+
+	/// Move all output to the given queue.
+	pub(super) fn move_output(&mut self, dest: &mut VecDeque<Bytes>) {
+		dest.append(&mut self.output)
+	}
+
+	/// The connection ID.
+	pub(super) fn id(&self) -> Id {
+		self.id
+	}
+
+	/// Add a CLOSE frame with the given status code to the output queue.
+	fn send_close(&mut self, code: u16) {
+		log::trace!("{}: closing connection", self.id);
+		#[cfg(feature = "tracing")]
+		tracing::debug!(parent: &self.span, code, "closing connection");
+		self.close_sent = true;
+		self.encode(Header::new(OpCode::Close), code.to_be_bytes().to_vec())
+	}
+
+	/// Check the given header of a new frame and prepare receiving its payload.
+	///
+	/// Data frame payloads are appended to the message buffer.
+	fn start_frame(&mut self, header: &Header) -> Result<(), Error> {
+		if header.opcode().is_control() || header.opcode().is_reserved_control() {
+			return Ok(());
+		}
+
+		match (header.opcode(), &self.fragment) {
+			(OpCode::Continue, None) => {
+				log::debug!("{}: continue frame while not processing message fragments", self.id);
+				return Err(Error::UnexpectedOpCode(OpCode::Continue));
+			}
+			(OpCode::Continue, Some(_)) => {}
+			(oc, Some(_)) => {
+				log::debug!("{}: new message while processing fragmented message", self.id);
+				return Err(Error::UnexpectedOpCode(oc));
+			}
+			(_, None) => {}
+		}
+
+		let length = self.message.len().saturating_add(header.payload_len());
+
+		// Check if total message does not exceed maximum.
+		if length > self.max_message_size {
+			log::warn!("{}: accumulated message length exceeds maximum", self.id);
+			self.reset_message();
+			return Err(Error::MessageTooLarge { current: length, maximum: self.max_message_size });
+		}
+
+		if self.extensions.is_empty() || self.message.is_empty() {
+			// The payload data is appended to the message directly.
+			self.frame_offset = self.message.len();
+			self.message.resize(length, 0u8);
+		} else {
+			// Extensions decode every frame on its own, cf. `Protocol::decode_frame`.
+			self.separate_frame = true;
+			self.frame_offset = 0;
+			self.frame.clear();
+			self.frame.resize(header.payload_len(), 0u8);
+		}
+		self.filled = self.frame_offset;
+
+		Ok(())
+	}
+
+	/// Skip over the payload bytes of a frame we are not interested in.
+	///
+	/// Returns `false` if more bytes need to be received first.
+	fn discard_payload(&mut self) -> bool {
+		let n = std::cmp::min(self.discard, self.input.len());
+		self.input.advance(n);
+		self.discard -= n;
+		if self.discard > 0 {
+			self.needed = std::cmp::min(self.discard, DISCARD_BLOCK_SIZE);
+			return false;
+		}
+		true
+	}
+
+	/// Apply all extensions to the payload data of the frame just received.
+	fn decode_frame(&mut self, header: &mut Header, frame: FrameContext) -> Result<(), Error> {
+		if self.extensions.is_empty() {
+			return Ok(());
+		}
+		// Initial frames are at the start of the message buffer, all others are received separately.
+		let id = self.id;
+		let data = if self.separate_frame { &mut self.frame } else { &mut self.message };
+		let result = self.extensions.iter_mut().try_for_each(|e| {
+			log::trace!("{}: decoding frame with extension: {}", id, e.name());
+			e.decode_frame(header, data, frame).map_err(extension_error)
+		});
+		if self.separate_frame {
+			self.message.extend_from_slice(&self.frame);
+			self.frame.clear();
+			self.separate_frame = false
+		}
+		result
+	}
+
+	/// Forget about the message which extensions failed to decode.
+	///
+	/// If the decoded message would be too large, a CLOSE frame with status
+	/// code 1009 (message too big) is added to the output queue.
+	fn on_decode_error(&mut self, e: Error) -> Error {
+		self.reset_message();
+		if let Error::MessageTooLarge { .. } = e {
+			log::debug!("{}: decoded message too large", self.id);
+			self.send_close(1009)
+		}
+		e
+	}
+
+	/// Forget about the message currently being received.
+	fn reset_message(&mut self) {
+		self.message.clear();
+		self.fragment = None;
+		self.frame.clear();
+		self.separate_frame = false;
+		self.frame_offset = 0;
+		self.filled = 0;
+	}
+
+	/// Answer incoming control frames.
+	/// `PING`: replied to with a `PONG`
+	/// `CLOSE`: replied to with a `CLOSE` and the connection is closed down
+	/// Reserved opcodes are passed on to extensions, cf. [`Protocol::on_reserved`]
+	/// All other [`OpCode`]s return [`Error::UnexpectedOpCode`]
+	fn on_control(&mut self, header: &Header, data: Bytes) -> Result<Option<Event>, Error> {
+		match header.opcode() {
+			OpCode::Ping => {
+				#[cfg(feature = "tracing")]
+				tracing::trace!(parent: &self.span, len = data.len(), "ping received, answering with pong");
+				self.encode(Header::new(OpCode::Pong), data.to_vec());
+				Ok(Some(Event::Ping(data)))
+			}
+			OpCode::Pong => Ok(Some(Event::Pong(data))),
+			OpCode::Close => {
+				log::trace!("{}: Acknowledging CLOSE to sender", self.id);
+				let (header, reason) = close_answer(&data)?;
+				// Write back a Close frame, unless we have sent one already.
+				if self.close_sent {
+					log::trace!("{}: CLOSE has been sent already", self.id)
+				} else if let Some(CloseReason { code, .. }) = reason {
+					self.encode(header, code.to_be_bytes().to_vec())
+				} else {
+					self.encode(header, Vec::new())
+				}
+				self.is_closed = true;
+				if let Some(close_reason) = &reason {
+					log::trace!("{}: recv, incoming CLOSE: {:?}", self.id, close_reason);
+					#[cfg(feature = "tracing")]
+					tracing::debug!(
+						parent: &self.span,
+						code = close_reason.code,
+						reason = close_reason.descr.as_deref(),
+						"close received"
+					);
+				}
+				Ok(Some(Event::Closed(reason)))
+			}
+			OpCode::Reserved11 | OpCode::Reserved12 | OpCode::Reserved13 | OpCode::Reserved14 | OpCode::Reserved15 => {
+				self.on_reserved(header, data)
+			}
+			OpCode::Binary
+			| OpCode::Text
+			| OpCode::Continue
+			| OpCode::Reserved3
+			| OpCode::Reserved4
+			| OpCode::Reserved5
+			| OpCode::Reserved6
+			| OpCode::Reserved7 => Err(Error::UnexpectedOpCode(header.opcode())),
+		}
+	}
+
+	/// Pass a frame with a reserved opcode to the extension using the opcode.
+	///
+	/// Returns `None` if the extension consumed the frame.
+	fn on_reserved(&mut self, header: &Header, data: Bytes) -> Result<Option<Event>, Error> {
+		let opcode = header.opcode();
+		if let Some(e) = self.extensions.iter_mut().find(|e| e.reserved_opcodes().contains(&opcode)) {
+			log::trace!("{}: {} frame for extension: {}", self.id, opcode, e.name());
+			if e.on_reserved_frame(header, &data).map_err(Error::Extension)? {
+				return Ok(None);
+			}
+		}
+		Ok(Some(Event::Reserved(opcode, data)))
+	}
+
+	/// Encode a frame into the output queue.
+	///
+	/// The payload data will be masked if necessary.
+	/// No extensions will be applied to header and payload data.
+	fn encode(&mut self, header: Header, mut data: Vec<u8>) {
+		let header = self.encode_header(header, data.len());
+		base::Codec::apply_mask(&header, &mut data);
+		self.output.push_back(Bytes::from(data))
+	}
+
+	/// Encode a frame header into the output queue.
+	///
+	/// Returns the header with the mask to apply to the payload data, if any.
+	fn encode_header(&mut self, mut header: Header, len: usize) -> Header {
+		if self.mode.is_client() {
+			header.set_masked(true);
+			header.set_mask(self.masks.next_u32());
+		}
+		header.set_payload_len(len);
+
+		log::trace!("{}: send: {}", self.id, header);
+		#[cfg(feature = "tracing")]
+		trace_frame(&self.span, "frame sent", &header);
+
+		self.output.push_back(Bytes::copy_from_slice(self.codec.encode_header(&header)));
+		header
+	}
+}
+
+/// Check that a frame with the given opcode and payload length may be sent.
+pub(super) fn check_frame(opcode: OpCode, len: usize) -> Result<(), Error> {
+	match opcode {
+		OpCode::Text | OpCode::Binary => Ok(()),
+		OpCode::Ping | OpCode::Pong if len <= MAX_CONTROL_PAYLOAD_SIZE => Ok(()),
+		OpCode::Ping | OpCode::Pong => Err(Error::MessageTooLarge { current: len, maximum: MAX_CONTROL_PAYLOAD_SIZE }),
+		oc => Err(Error::UnexpectedOpCode(oc)),
+	}
+}
+
+/// The reserved bits set in the given header, RSV1 being the most significant bit.
+fn reserved_bits(header: &Header) -> u8 {
+	u8::from(header.is_rsv1()) << 2 | u8::from(header.is_rsv2()) << 1 | u8::from(header.is_rsv3())
+}
+
+/// Check that an extension has only set the reserved bits it uses.
+fn check_reserved_bits(id: Id, extension: &dyn Extension, before: u8, header: &Header) -> Result<(), Error> {
+	if let Some(bit) = undeclared_bit(header, before, extension.reserved_bits()) {
+		log::debug!("{}: extension {} set undeclared reserved bits", id, extension.name());
+		return Err(Error::Codec(base::Error::InvalidReservedBit(bit)));
+	}
+	Ok(())
+}
+
+/// The first reserved bit (1 ..= 3) set in the header which was neither set
+/// before nor is one of the declared bits.
+fn undeclared_bit(header: &Header, before: u8, declared: (bool, bool, bool)) -> Option<u8> {
+	let (r1, r2, r3) = declared;
+	let declared = u8::from(r1) << 2 | u8::from(r2) << 1 | u8::from(r3);
+	let undeclared = reserved_bits(header) & !before & !declared;
+	if undeclared == 0 {
+		return None;
+	}
+	Some(undeclared.leading_zeros() as u8 - 4)
+}
+
+/// Emit a `tracing` event describing the given frame header.
+#[cfg(feature = "tracing")]
+fn trace_frame(span: &tracing::Span, message: &str, header: &Header) {
+	tracing::trace!(
+		parent: span,
+		opcode = %header.opcode(),
+		fin = header.is_fin(),
+		rsv1 = header.is_rsv1(),
+		len = header.payload_len(),
+		"{}",
+		message
+	)
+}
+
+/// Apply all extensions to the given header and message data.
+fn decode_with_extensions(
+	id: Id,
+	extensions: &mut [Box<dyn Extension + Send>],
+	header: &mut Header,
+	message: &mut Vec<u8>,
+) -> Result<(), Error> {
+	for e in extensions.iter_mut() {
+		log::trace!("{}: decoding with extension: {}", id, e.name());
+		e.decode(header, message).map_err(extension_error)?
+	}
+	Ok(())
+}
+
+/// Turn an error of an extension into a connection error.
+fn extension_error(e: BoxedError) -> Error {
+	match e.downcast::<extension::MessageTooLarge>() {
+		Ok(e) => Error::MessageTooLarge { current: e.current, maximum: e.maximum },
+		Err(e) => Error::Extension(e),
+	}
+}
+
+/// Create a close frame based on the given data. The close frame is echoed back
+/// to the sender.
+fn close_answer(data: &[u8]) -> Result<(Header, Option<CloseReason>), Error> {
+	let answer = Header::new(OpCode::Close);
+	if data.len() < 2 {
+		return Ok((answer, None));
+	}
+	// Check that the reason string is properly encoded
+	let descr = std::str::from_utf8(&data[2..])?.into();
+	let code = u16::from_be_bytes([data[0], data[1]]);
+	let reason = CloseReason { code, descr: Some(descr) };
+
+	// Status codes are defined in
+	// https://tools.ietf.org/html/rfc6455#section-7.4.1 and
+	// https://mailarchive.ietf.org/arch/msg/hybi/P_1vbD9uyHl63nbIIbFxKMfSwcM/
+	match code {
+        | 1000 ..= 1003
+        | 1007 ..= 1011
+        | 1012 // Service Restart
+        | 1013 // Try Again Later
+        | 1015
+        | 3000 ..= 4999 => Ok((answer, Some(reason))), // acceptable codes
+        _               => {
+            // invalid code => protocol error (1002)
+            Ok((answer, Some(CloseReason { code: 1002, descr: None})))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Event, Protocol};
+	use crate::{
+		base::{self, Header, OpCode},
+		connection::{Error, Mode},
+		data::Data,
+		extension::{Extension, Fragment, FrameContext, Param},
+		mask::Seeded,
+		BoxedError, Storage,
+	};
+	use std::sync::{Arc, Mutex};
+
+	#[test]
+	fn events_and_answers_without_io() {
+		let mut protocol = Protocol::new(Mode::Server);
+		let mut message = Vec::new();
+		assert_eq!(None, protocol.next_event(&mut message).unwrap());
+		assert_eq!(2, protocol.bytes_needed());
+
+		// A fragmented message with a PING in between.
+		protocol.receive_bytes(&[0x01, 0x02, b'h', b'e', 0x89, 0x01, 7, 0x80, 0x03, b'l', b'l']);
+		assert_eq!(Some(Event::Ping(vec![7].into())), protocol.next_event(&mut message).unwrap());
+		assert_eq!(Some(&[0x8a, 0x01][..]), protocol.take_output().as_deref());
+		assert_eq!(Some(&[7][..]), protocol.take_output().as_deref());
+		assert_eq!(None, protocol.next_event(&mut message).unwrap());
+		assert_eq!(1, protocol.bytes_needed());
+		assert!(message.is_empty());
+
+		protocol.receive_bytes(b"o");
+		assert_eq!(Some(Event::Data(Data::Text(5))), protocol.next_event(&mut message).unwrap());
+		assert_eq!(b"hello", &message[..]);
+
+		protocol.send(OpCode::Binary, vec![1, 2]).unwrap();
+		assert_eq!(Some(&[0x82, 0x02][..]), protocol.take_output().as_deref());
+		assert_eq!(Some(&[1, 2][..]), protocol.take_output().as_deref());
+		assert!(!protocol.has_output());
+
+		// A CLOSE is echoed and ends the connection.
+		protocol.receive_bytes(&[0x88, 0x02, 0x03, 0xe8]);
+		assert!(matches!(protocol.next_event(&mut message), Ok(Some(Event::Closed(Some(r)))) if r.code == 1000));
+		assert!(protocol.is_closed());
+		assert_eq!(Some(&[0x88, 0x02][..]), protocol.take_output().as_deref());
+		assert!(protocol.next_event(&mut message).is_err());
+	}
+
+	/// An extension which uses two reserved opcodes and consumes control frames.
+	#[derive(Debug, Default)]
+	struct Opcodes {
+		consumed: Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl Extension for Opcodes {
+		fn is_enabled(&self) -> bool {
+			true
+		}
+
+		fn name(&self) -> &str {
+			"opcodes"
+		}
+
+		fn params(&self) -> &[Param<'_>] {
+			&[]
+		}
+
+		fn configure(&mut self, _: &[Param]) -> Result<(), BoxedError> {
+			Ok(())
+		}
+
+		fn reserved_opcodes(&self) -> &[OpCode] {
+			&[OpCode::Reserved3, OpCode::Reserved11]
+		}
+
+		fn on_reserved_frame(&mut self, header: &Header, data: &[u8]) -> Result<bool, BoxedError> {
+			if header.opcode() == OpCode::Reserved11 {
+				self.consumed.lock().unwrap().extend_from_slice(data);
+				return Ok(true);
+			}
+			Ok(false)
+		}
+	}
+
+	/// Frame context (`None` for messages) and payload length of decoded data.
+	type Decoded = Vec<(Option<FrameContext>, usize)>;
+
+	/// An extension which records the frames and messages it decodes.
+	#[derive(Debug, Default)]
+	struct Recorder {
+		decoded: Arc<Mutex<Decoded>>,
+	}
+
+	impl Extension for Recorder {
+		fn is_enabled(&self) -> bool {
+			true
+		}
+
+		fn name(&self) -> &str {
+			"recorder"
+		}
+
+		fn params(&self) -> &[Param<'_>] {
+			&[]
+		}
+
+		fn configure(&mut self, _: &[Param]) -> Result<(), BoxedError> {
+			Ok(())
+		}
+
+		fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+			assert!(header.is_fin());
+			self.decoded.lock().unwrap().push((None, data.len()));
+			Ok(())
+		}
+
+		fn decode_frame(&mut self, _: &mut Header, data: &mut Vec<u8>, frame: FrameContext) -> Result<(), BoxedError> {
+			self.decoded.lock().unwrap().push((Some(frame), data.len()));
+			data.make_ascii_uppercase();
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn extensions_see_frames_and_messages() {
+		let mut protocol = Protocol::new(Mode::Server);
+		let recorder = Recorder::default();
+		let decoded = recorder.decoded.clone();
+		protocol.add_extensions(Some(Box::new(recorder) as Box<dyn Extension + Send>)).unwrap();
+		let mut message = Vec::new();
+		protocol.receive_bytes(&[0x01, 0x02, b'h', b'e', 0x00, 0x02, b'l', b'l', 0x80, 0x01, b'o', 0x82, 0x00]);
+		assert_eq!(Some(Event::Data(Data::Text(5))), protocol.next_event(&mut message).unwrap());
+		assert_eq!(b"HELLO", &message[..]);
+		assert_eq!(Some(Event::Data(Data::Binary(0))), protocol.next_event(&mut message).unwrap());
+		let frame = |f, o| Some(FrameContext::new(f, o));
+		assert_eq!(
+			vec![
+				(frame(Fragment::First, OpCode::Text), 2),
+				(frame(Fragment::Continuation, OpCode::Text), 2),
+				(frame(Fragment::Final, OpCode::Text), 1),
+				(None, 5),
+				(frame(Fragment::Unfragmented, OpCode::Binary), 0),
+				(None, 0)
+			],
+			*decoded.lock().unwrap()
+		);
+	}
+
+	#[test]
+	fn reserved_opcodes_are_routed() {
+		let mut protocol = Protocol::new(Mode::Server);
+		let mut message = Vec::new();
+		protocol.receive_bytes(&[0x84, 0x00]);
+		assert!(matches!(protocol.next_event(&mut message), Err(Error::Codec(_))));
+
+		let mut protocol = Protocol::new(Mode::Server);
+		let opcodes = Opcodes::default();
+		let consumed = opcodes.consumed.clone();
+		protocol.add_extensions(Some(Box::new(opcodes) as Box<dyn Extension + Send>)).unwrap();
+		// A fragmented reserved data frame with a consumed reserved control frame in between.
+		protocol.receive_bytes(&[0x03, 0x01, 1, 0x8b, 0x01, 9, 0x80, 0x01, 2]);
+		assert_eq!(
+			Some(Event::Reserved(OpCode::Reserved3, vec![1, 2].into())),
+			protocol.next_event(&mut message).unwrap()
+		);
+		assert!(message.is_empty());
+		assert!(!protocol.has_output());
+		assert_eq!(vec![9], *consumed.lock().unwrap());
+	}
+
+	/// An extension which declares RSV1 and sets the given bits when encoding.
+	#[derive(Debug)]
+	struct Rsv(&'static str, (bool, bool, bool));
+
+	impl Extension for Rsv {
+		fn is_enabled(&self) -> bool {
+			true
+		}
+
+		fn name(&self) -> &str {
+			self.0
+		}
+
+		fn params(&self) -> &[Param<'_>] {
+			&[]
+		}
+
+		fn configure(&mut self, _: &[Param]) -> Result<(), BoxedError> {
+			Ok(())
+		}
+
+		fn reserved_bits(&self) -> (bool, bool, bool) {
+			(true, false, false)
+		}
+
+		fn encode(&mut self, header: &mut Header, _: &mut Storage) -> Result<(), BoxedError> {
+			header.set_rsv1(self.1 .0).set_rsv2(self.1 .1).set_rsv3(self.1 .2);
+			Ok(())
+		}
+	}
+
+	#[cfg(feature = "tracing")]
+	#[test]
+	fn span_fields_are_recorded() {
+		use std::fmt;
+		use tracing::{
+			field::{Field, Visit},
+			span, Event, Metadata, Subscriber,
+		};
+
+		/// Appends `name=value` of every field visited.
+		struct Fields<'a>(&'a mut Vec<String>);
+
+		impl Visit for Fields<'_> {
+			fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+				self.0.push(format!("{}={:?}", field.name(), value))
+			}
+		}
+
+		/// A subscriber which records the fields of all spans.
+		struct Spans(Arc<Mutex<Vec<String>>>);
+
+		impl Subscriber for Spans {
+			fn enabled(&self, _: &Metadata<'_>) -> bool {
+				true
+			}
+
+			fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+				span.record(&mut Fields(&mut self.0.lock().unwrap()));
+				span::Id::from_u64(1)
+			}
+
+			fn record(&self, _: &span::Id, values: &span::Record<'_>) {
+				values.record(&mut Fields(&mut self.0.lock().unwrap()))
+			}
+
+			fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+			fn event(&self, _: &Event<'_>) {}
+
+			fn enter(&self, _: &span::Id) {}
+
+			fn exit(&self, _: &span::Id) {}
+		}
+
+		let fields = Arc::new(Mutex::new(Vec::new()));
+		tracing::subscriber::with_default(Spans(fields.clone()), || {
+			let mut protocol = Protocol::new(Mode::Client);
+			protocol.set_id(42);
+			protocol.set_mask_source(Box::new(Seeded::new(7)));
+			protocol.add_extensions(vec![Box::new(Rsv("rsv", (true, false, false))) as Box<_>]).unwrap();
+		});
+
+		let fields = fields.lock().unwrap();
+		assert!(fields.contains(&"mode=Client".to_string()), "{:?}", fields);
+		assert!(fields.contains(&"extensions=\"rsv\"".to_string()), "{:?}", fields);
+		// The explicit ID is not replaced by the mask source.
+		assert_eq!(Some("id=0000002a"), fields.iter().rev().find(|f| f.starts_with("id=")).map(String::as_str));
+	}
+
+	#[test]
+	fn reserved_bits_are_checked() {
+		let mut protocol = Protocol::new(Mode::Server);
+		let a: Box<dyn Extension + Send> = Box::new(Rsv("a", (true, false, false)));
+		let b: Box<dyn Extension + Send> = Box::new(Rsv("b", (false, false, false)));
+		let e = protocol.add_extensions(vec![a, b]).unwrap_err();
+		assert_eq!(1, e.bit());
+		assert_eq!(("a", "b"), e.extensions());
+		assert!(protocol.extensions.is_empty());
+
+		protocol.add_extensions(Some(Box::new(Rsv("a", (true, false, false))) as Box<dyn Extension + Send>)).unwrap();
+		protocol.send(OpCode::Text, b"hi".to_vec()).unwrap();
+		assert_eq!(Some(&[0xc1, 0x02][..]), protocol.take_output().as_deref());
+
+		let mut protocol = Protocol::new(Mode::Server);
+		protocol.add_extensions(Some(Box::new(Rsv("c", (true, false, true))) as Box<dyn Extension + Send>)).unwrap();
+		assert!(matches!(
+			protocol.send(OpCode::Text, b"hi".to_vec()),
+			Err(Error::Codec(base::Error::InvalidReservedBit(3)))
+		));
+		assert!(!protocol.has_output());
+	}
+
+	#[test]
+	fn raw_frames_are_checked() {
+		let mut protocol = Protocol::new(Mode::Server);
+		protocol.add_extensions(vec![Box::new(Rsv("rsv", (true, false, false))) as Box<_>]).unwrap();
+
+		let mut header = Header::new(OpCode::Binary);
+		header.set_rsv2(true);
+		let result = protocol.send_raw_frame(header, vec![1]);
+		assert!(matches!(result, Err(Error::Codec(base::Error::InvalidReservedBit(2)))));
+		assert!(!protocol.has_output());
+		let mut header = Header::new(OpCode::Binary);
+		header.set_rsv1(true);
+		protocol.send_raw_frame(header, vec![1]).unwrap();
+		assert_eq!(Some(&[0xc2, 0x01][..]), protocol.take_output().as_deref());
+		protocol.take_output();
+
+		// A raw CLOSE frame is not sent a second time when the remote end answers.
+		protocol.send_raw_frame(Header::new(OpCode::Close), 1000u16.to_be_bytes().to_vec()).unwrap();
+		assert_eq!(Some(&[0x88, 0x02][..]), protocol.take_output().as_deref());
+		protocol.take_output();
+		protocol.receive_bytes(&[0x88, 0x00]);
+		assert_eq!(Some(Event::Closed(None)), protocol.next_event(&mut Vec::new()).unwrap());
+		assert!(!protocol.has_output());
+	}
+
+	#[test]
+	fn extensions_can_be_skipped() {
+		let mut protocol = Protocol::new(Mode::Server);
+		protocol.add_extensions(Some(Box::new(Rsv("a", (true, false, false))) as Box<dyn Extension + Send>)).unwrap();
+		protocol.send_uncompressed(OpCode::Binary, b"hi".to_vec()).unwrap();
+		assert_eq!(Some(&[0x82, 0x02][..]), protocol.take_output().as_deref());
+		assert_eq!(Some(&b"hi"[..]), protocol.take_output().as_deref());
+		protocol.send(OpCode::Binary, b"hi".to_vec()).unwrap();
+		assert_eq!(Some(&[0xc2, 0x02][..]), protocol.take_output().as_deref());
+	}
+
+	#[test]
+	fn client_frames_are_masked() {
+		let mut protocol = Protocol::new(Mode::Client);
+		protocol.set_mask_source(Box::new(Seeded::new(7)));
+		protocol.send(OpCode::Text, b"hello".to_vec()).unwrap();
+		let header = protocol.take_output().unwrap();
+		let mut data = protocol.take_output().unwrap().to_vec();
+		assert_eq!(&[0x81, 0x85], &header[..2]);
+		for (i, b) in data.iter_mut().enumerate() {
+			*b ^= header[2 + i % 4]
+		}
+		assert_eq!(b"hello", &data[..]);
+	}
+
+	/// A client and server protocol with permessage-deflate enabled.
+	#[cfg(feature = "deflate")]
+	fn with_deflate() -> (Protocol, Protocol) {
+		use crate::extension::deflate::Deflate;
+
+		let mut client_deflate = Deflate::new(Mode::Client);
+		let mut server_deflate = Deflate::new(Mode::Server);
+		server_deflate
+			.configure(&client_deflate.params().iter().cloned().map(Param::acquire).collect::<Vec<_>>())
+			.unwrap();
+		client_deflate
+			.configure(&server_deflate.params().iter().cloned().map(Param::acquire).collect::<Vec<_>>())
+			.unwrap();
+
+		let mut client = Protocol::new(Mode::Client);
+		client.add_extensions([Box::new(client_deflate) as Box<dyn Extension + Send>]).unwrap();
+		let mut server = Protocol::new(Mode::Server);
+		server.add_extensions([Box::new(server_deflate) as Box<dyn Extension + Send>]).unwrap();
+		(client, server)
+	}
+
+	#[cfg(feature = "deflate")]
+	#[test]
+	fn decompression_is_limited() {
+		let (mut client, mut server) = with_deflate();
+		client.send(OpCode::Binary, vec![0; 1024 * 1024]).unwrap();
+		server.set_max_message_size(64 * 1024);
+		while let Some(bytes) = client.take_output() {
+			server.receive_bytes(&bytes)
+		}
+		let mut message = Vec::new();
+		assert!(matches!(server.next_event(&mut message), Err(Error::MessageTooLarge { maximum: 65536, .. })));
+
+		// The connection is closed with 1009 (message too big).
+		assert_eq!(Some(&[0x88, 0x02][..]), server.take_output().as_deref());
+		assert_eq!(Some(&[0x03, 0xf1][..]), server.take_output().as_deref());
+	}
+
+	#[test]
+	fn fragments_are_sent() {
+		let mut protocol = Protocol::new(Mode::Server);
+		protocol.send_fragment(OpCode::Text, b"he".to_vec(), false).unwrap();
+		assert_eq!(Some(&[0x01, 0x02][..]), protocol.take_output().as_deref());
+		protocol.take_output();
+
+		// Control frames may be sent in between, other messages may not.
+		protocol.send(OpCode::Ping, Vec::new()).unwrap();
+		assert_eq!(Some(&[0x89, 0x00][..]), protocol.take_output().as_deref());
+		protocol.take_output();
+		assert!(matches!(protocol.send(OpCode::Binary, Vec::new()), Err(Error::UnexpectedOpCode(OpCode::Binary))));
+		assert!(matches!(
+			protocol.send_fragment(OpCode::Binary, Vec::new(), true),
+			Err(Error::UnexpectedOpCode(OpCode::Binary))
+		));
+
+		protocol.send_fragment(OpCode::Text, b"ll".to_vec(), false).unwrap();
+		assert_eq!(Some(&[0x00, 0x02][..]), protocol.take_output().as_deref());
+		protocol.take_output();
+		protocol.send_fragment(OpCode::Text, b"o".to_vec(), true).unwrap();
+		assert_eq!(Some(&[0x80, 0x01][..]), protocol.take_output().as_deref());
+		protocol.take_output();
+
+		// A single fragment is a complete message.
+		protocol.send_fragment(OpCode::Binary, b"x".to_vec(), true).unwrap();
+		assert_eq!(Some(&[0x82, 0x01][..]), protocol.take_output().as_deref());
+		protocol.take_output();
+		assert!(matches!(
+			protocol.send_fragment(OpCode::Ping, Vec::new(), true),
+			Err(Error::UnexpectedOpCode(OpCode::Ping))
+		));
+	}
+
+	#[test]
+	fn fragments_are_refused_without_extension_support() {
+		let mut protocol = Protocol::new(Mode::Server);
+		protocol.add_extensions(vec![Box::new(Rsv("rsv", (true, false, false))) as Box<_>]).unwrap();
+		assert!(matches!(protocol.send_fragment(OpCode::Text, b"he".to_vec(), false), Err(Error::Extension(_))));
+		assert!(protocol.take_output().is_none());
+
+		// Complete messages are still passed to `Extension::encode`.
+		protocol.send_fragment(OpCode::Text, b"hello".to_vec(), true).unwrap();
+		assert_eq!(Some(&[0xc1, 0x05][..]), protocol.take_output().as_deref());
+	}
+
+	#[cfg(feature = "deflate")]
+	#[test]
+	fn fragments_are_compressed() {
+		let (mut client, mut server) = with_deflate();
+		let text = "All work and no play makes Jack a dull boy. ".repeat(100);
+		let chunks = text.as_bytes().chunks(1000).collect::<Vec<_>>();
+		for (i, chunk) in chunks.iter().enumerate() {
+			client.send_fragment(OpCode::Text, chunk.to_vec(), i == chunks.len() - 1).unwrap()
+		}
+		let mut len = 0;
+		while let Some(bytes) = client.take_output() {
+			len += bytes.len();
+			server.receive_bytes(&bytes)
+		}
+		assert!(len < text.len() / 4);
+		let mut message = Vec::new();
+		assert_eq!(Some(Event::Data(Data::Text(text.len()))), server.next_event(&mut message).unwrap());
+		assert_eq!(text.as_bytes(), &message[..]);
+	}
+}
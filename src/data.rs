@@ -10,9 +10,10 @@
 
 use std::fmt;
 
-use crate::connection::CloseReason;
+use crate::{base::OpCode, connection::CloseReason};
 
 /// Data received from the remote end.
+#[non_exhaustive]
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Incoming<'a> {
 	/// Text or binary data.
@@ -21,25 +22,27 @@ pub enum Incoming<'a> {
 	Pong(&'a [u8]),
 	/// The other end closed the connection.
 	Closed(CloseReason),
+	/// A frame with a reserved opcode used by an extension.
+	///
+	/// Fragmented data frames are reassembled, i.e. this is the complete
+	/// message payload.
+	Reserved(OpCode, &'a [u8]),
 }
 
 impl Incoming<'_> {
 	/// Is this text or binary data?
 	pub fn is_data(&self) -> bool {
-		if let Incoming::Data(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Incoming::Data(_))
 	}
 
 	/// Is this a PONG?
 	pub fn is_pong(&self) -> bool {
-		if let Incoming::Pong(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Incoming::Pong(_))
+	}
+
+	/// Is this a frame with a reserved opcode?
+	pub fn is_reserved(&self) -> bool {
+		matches!(self, Incoming::Reserved(..))
 	}
 
 	/// Is this text data?
@@ -72,20 +75,12 @@ pub enum Data {
 impl Data {
 	/// Is this text data?
 	pub fn is_text(&self) -> bool {
-		if let Data::Text(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Data::Text(_))
 	}
 
 	/// Is this binary data?
 	pub fn is_binary(&self) -> bool {
-		if let Data::Binary(_) = self {
-			true
-		} else {
-			false
-		}
+		matches!(self, Data::Binary(_))
 	}
 
 	/// The length of data (number of bytes).
@@ -95,6 +90,11 @@ impl Data {
 			Data::Binary(n) => *n,
 		}
 	}
+
+	/// Is the data empty?
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
 }
 
 /// Wrapper type which restricts the length of its byte slice to 125 bytes.
@@ -11,10 +11,17 @@
 //!
 //! [rfc6455]: https://tools.ietf.org/html/rfc6455#section-9
 
-#[cfg(feature = "deflate")]
+#[cfg(feature = "flate2")] // enabled by any of the `deflate*` features
 pub mod deflate;
+#[cfg(feature = "flate2")]
+pub mod deflate_frame;
+#[cfg(feature = "zstd")]
+pub mod zstd;
 
-use crate::{base::Header, BoxedError, Storage};
+use crate::{
+	base::{Header, OpCode},
+	BoxedError, Storage,
+};
 use std::{borrow::Cow, fmt};
 
 /// A websocket extension as per RFC 6455, section 9.
@@ -27,25 +34,44 @@ use std::{borrow::Cow, fmt};
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When receiving a handshake request from a client, for each extension
-/// with a matching name, [`Extension::configure`] will be applied to the
-/// request parameters. The extension may internally enable itself.
+///    with a matching name, [`Extension::configure`] will be applied to the
+///    request parameters. The extension may internally enable itself.
+///    If the client offers an extension several times, the offers are
+///    considered in order until the extension has enabled itself.
 /// 3. When sending back the response, for each extension whose
-/// [`Extension::is_enabled`] returns true, the extension name and its
-/// parameters (as returned by [`Extension::params`]) will be included in the
-/// response.
+///    [`Extension::is_enabled`] returns true, the extension name and its
+///    parameters (as returned by [`Extension::params`]) will be included in the
+///    response.
 ///
 /// # Client
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When creating the handshake request, all extensions and its parameters
-/// (as returned by [`Extension::params`]) will be included in the request.
+///    (as returned by [`Extension::params`]) will be included in the request.
 /// 3. When receiving the response from the server, for every extension with
-/// a matching name in the response, [`Extension::configure`] will be applied
-/// to the response parameters. The extension may internally enable itself.
+///    a matching name in the response, [`Extension::configure`] will be applied
+///    to the response parameters. The extension may internally enable itself.
+///    If several extensions have the same name, i.e. alternative offers have
+///    been made, only the first one which enables itself is used.
 ///
 /// After this handshake phase, extensions have been configured and are
 /// potentially enabled. Enabled extensions can then be used for further base
 /// frame processing.
+///
+/// # Frames and messages
+///
+/// Data is passed to extensions at two levels. [`Extension::encode`] and
+/// [`Extension::decode`] are invoked once per complete message, e.g. to
+/// compress or decompress all of its payload data. [`Extension::encode_frame`]
+/// and [`Extension::decode_frame`] are invoked for every single data frame,
+/// together with its position within the message. When sending, the message
+/// hook runs before the frame hooks; when receiving, the frame hooks run
+/// before the fragments are reassembled and passed to the message hook.
+/// Messages which are sent in fragments, e.g. with
+/// [`Sender::send_fragment`](crate::connection::Sender::send_fragment), are
+/// never complete and only passed to the frame hooks. They are refused while
+/// an enabled extension does not support this, cf.
+/// [`Extension::encodes_fragments`].
 pub trait Extension: std::fmt::Debug {
 	/// Is this extension enabled?
 	fn is_enabled(&self) -> bool;
@@ -54,24 +80,91 @@ pub trait Extension: std::fmt::Debug {
 	fn name(&self) -> &str;
 
 	/// The parameters this extension wants to send for negotiation.
-	fn params(&self) -> &[Param];
+	fn params(&self) -> &[Param<'_>];
 
 	/// Configure this extension with the parameters received from negotiation.
 	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError>;
 
-	/// Encode a frame, given as frame header and payload data.
-	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError>;
+	/// Encode a message, given as frame header and payload data.
+	fn encode(&mut self, _header: &mut Header, _data: &mut Storage) -> Result<(), BoxedError> {
+		Ok(())
+	}
 
-	/// Decode a frame.
+	/// Decode a message.
 	///
-	/// The frame header is given, as well as the accumulated payload data, i.e.
-	/// the concatenated payload data of all message fragments.
-	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError>;
+	/// This is invoked once per message. The header is the one of the initial
+	/// frame with the FIN bit set and the payload data is the concatenated
+	/// payload data of all message fragments. Both include the changes made
+	/// by [`Extension::decode_frame`].
+	fn decode(&mut self, _header: &mut Header, _data: &mut Vec<u8>) -> Result<(), BoxedError> {
+		Ok(())
+	}
+
+	/// Encode a single data frame, given as frame header and payload data.
+	fn encode_frame(
+		&mut self,
+		_header: &mut Header,
+		_data: &mut Storage,
+		_frame: FrameContext,
+	) -> Result<(), BoxedError> {
+		Ok(())
+	}
+
+	/// Decode a single data frame.
+	///
+	/// The payload data is unmasked and belongs to this frame only.
+	fn decode_frame(
+		&mut self,
+		_header: &mut Header,
+		_data: &mut Vec<u8>,
+		_frame: FrameContext,
+	) -> Result<(), BoxedError> {
+		Ok(())
+	}
+
+	/// Can this extension encode messages which are sent in fragments?
+	///
+	/// Fragmented messages are only passed to [`Extension::encode_frame`]
+	/// and refused while an enabled extension returns `false`, so that no
+	/// message skips [`Extension::encode`]. Extensions which encode each
+	/// fragment or which do not encode messages at all should return `true`.
+	fn encodes_fragments(&self) -> bool {
+		false
+	}
 
 	/// The reserved bits this extension uses.
+	///
+	/// No two enabled extensions may use the same reserved bit and
+	/// [`Extension::encode`] and [`Extension::encode_frame`] must not set
+	/// any other reserved bits.
 	fn reserved_bits(&self) -> (bool, bool, bool) {
 		(false, false, false)
 	}
+
+	/// Set the maximum size of a complete message.
+	///
+	/// This is the limit configured for the connection. Extensions which
+	/// increase the size of received messages, e.g. by decompressing them,
+	/// should fail with [`MessageTooLarge`] instead of exceeding it.
+	fn set_max_message_size(&mut self, _max: usize) {}
+
+	/// The reserved opcodes this extension uses.
+	///
+	/// Opcodes 3 to 7 denote data frames, opcodes 11 to 15 control frames.
+	/// Frames with these opcodes are accepted while the extension is
+	/// enabled and passed to [`Extension::on_reserved_frame`].
+	fn reserved_opcodes(&self) -> &[OpCode] {
+		&[]
+	}
+
+	/// Handle a frame with one of the [`Extension::reserved_opcodes`].
+	///
+	/// Data frames are reassembled and decoded before. Returns `true` if
+	/// the frame has been consumed, otherwise it is passed on to the
+	/// application as [`Incoming::Reserved`](crate::Incoming::Reserved).
+	fn on_reserved_frame(&mut self, _header: &Header, _data: &[u8]) -> Result<bool, BoxedError> {
+		Ok(false)
+	}
 }
 
 impl<E: Extension + ?Sized> Extension for Box<E> {
@@ -83,7 +176,7 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
 		(**self).name()
 	}
 
-	fn params(&self) -> &[Param] {
+	fn params(&self) -> &[Param<'_>] {
 		(**self).params()
 	}
 
@@ -99,9 +192,152 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
 		(**self).decode(header, data)
 	}
 
+	fn encode_frame(&mut self, header: &mut Header, data: &mut Storage, frame: FrameContext) -> Result<(), BoxedError> {
+		(**self).encode_frame(header, data, frame)
+	}
+
+	fn decode_frame(&mut self, header: &mut Header, data: &mut Vec<u8>, frame: FrameContext) -> Result<(), BoxedError> {
+		(**self).decode_frame(header, data, frame)
+	}
+
+	fn encodes_fragments(&self) -> bool {
+		(**self).encodes_fragments()
+	}
+
 	fn reserved_bits(&self) -> (bool, bool, bool) {
 		(**self).reserved_bits()
 	}
+
+	fn set_max_message_size(&mut self, max: usize) {
+		(**self).set_max_message_size(max)
+	}
+
+	fn reserved_opcodes(&self) -> &[OpCode] {
+		(**self).reserved_opcodes()
+	}
+
+	fn on_reserved_frame(&mut self, header: &Header, data: &[u8]) -> Result<bool, BoxedError> {
+		(**self).on_reserved_frame(header, data)
+	}
+}
+
+/// Error returned by extensions if a decoded message would exceed the
+/// maximum message size.
+///
+/// The connection turns this error into
+/// [`connection::Error::MessageTooLarge`](crate::connection::Error::MessageTooLarge)
+/// and closes with status code 1009 (message too big).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTooLarge {
+	/// The size of the message so far.
+	pub current: usize,
+	/// The maximum size.
+	pub maximum: usize,
+}
+
+impl fmt::Display for MessageTooLarge {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "message too large: len >= {}, maximum = {}", self.current, self.maximum)
+	}
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// Error returned if two extensions use the same reserved bit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservedBitConflict {
+	bit: u8,
+	first: String,
+	second: String,
+}
+
+impl ReservedBitConflict {
+	/// The reserved bit in question (1, 2 or 3).
+	pub fn bit(&self) -> u8 {
+		self.bit
+	}
+
+	/// The names of both extensions using the reserved bit.
+	pub fn extensions(&self) -> (&str, &str) {
+		(&self.first, &self.second)
+	}
+}
+
+impl fmt::Display for ReservedBitConflict {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "extensions {} and {} both use reserved bit {}", self.first, self.second, self.bit)
+	}
+}
+
+impl std::error::Error for ReservedBitConflict {}
+
+/// Check that no two enabled extensions use the same reserved bit.
+pub(crate) fn check_reserved_bits<'a, I>(extensions: I) -> Result<(), ReservedBitConflict>
+where
+	I: IntoIterator<Item = &'a Box<dyn Extension + Send>>,
+{
+	let mut used: [Option<&str>; 3] = [None; 3];
+	for e in extensions.into_iter().filter(|e| e.is_enabled()) {
+		let (r1, r2, r3) = e.reserved_bits();
+		for (i, bit) in [r1, r2, r3].into_iter().enumerate() {
+			if !bit {
+				continue;
+			}
+			if let Some(other) = used[i] {
+				return Err(ReservedBitConflict { bit: i as u8 + 1, first: other.into(), second: e.name().into() });
+			}
+			used[i] = Some(e.name())
+		}
+	}
+	Ok(())
+}
+
+/// The position of a data frame within its message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fragment {
+	/// The frame is a complete message.
+	Unfragmented,
+	/// The initial frame of a fragmented message.
+	First,
+	/// An intermediate frame of a fragmented message.
+	Continuation,
+	/// The last frame of a fragmented message.
+	Final,
+}
+
+/// Describes a data frame passed to [`Extension::encode_frame`] or
+/// [`Extension::decode_frame`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameContext {
+	fragment: Fragment,
+	opcode: OpCode,
+}
+
+impl FrameContext {
+	/// Create a new frame context.
+	pub fn new(fragment: Fragment, opcode: OpCode) -> Self {
+		FrameContext { fragment, opcode }
+	}
+
+	/// The position of the frame within its message.
+	pub fn fragment(&self) -> Fragment {
+		self.fragment
+	}
+
+	/// The opcode of the message, i.e. of its initial frame.
+	pub fn opcode(&self) -> OpCode {
+		self.opcode
+	}
+
+	/// Is this the initial frame of a message?
+	pub fn is_first(&self) -> bool {
+		matches!(self.fragment, Fragment::Unfragmented | Fragment::First)
+	}
+
+	/// Is this the last frame of a message?
+	pub fn is_last(&self) -> bool {
+		matches!(self.fragment, Fragment::Unfragmented | Fragment::Final)
+	}
 }
 
 /// Extension parameter (used for negotiation).
@@ -148,3 +384,61 @@ impl<'a> Param<'a> {
 		Param { name: Cow::Owned(self.name.into_owned()), value: self.value.map(|v| Cow::Owned(v.into_owned())) }
 	}
 }
+
+/// Helpers to test extensions against each other.
+#[cfg(all(test, any(feature = "flate2", feature = "zstd")))]
+mod tests {
+	use super::{Extension, Param};
+	use crate::{
+		base::{Header, OpCode},
+		Storage,
+	};
+
+	/// Let the server configure itself with the client's offer.
+	pub(super) fn offer<E: Extension>(client: &E, server: &mut E) {
+		server.configure(&client.params().iter().cloned().map(Param::acquire).collect::<Vec<_>>()).unwrap()
+	}
+
+	/// Negotiate between the given client and server.
+	pub(super) fn negotiate<E: Extension>(mut client: E, mut server: E) -> (E, E) {
+		offer(&client, &mut server);
+		assert!(server.is_enabled());
+		client.configure(&server.params().iter().cloned().map(Param::acquire).collect::<Vec<_>>()).unwrap();
+		assert!(client.is_enabled());
+		(client, server)
+	}
+
+	/// Send a message and return its compressed length.
+	pub(super) fn send<E: Extension>(sender: &mut E, receiver: &mut E, message: &[u8]) -> usize {
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Shared(message);
+		sender.encode(&mut header, &mut data).unwrap();
+		assert!(header.is_rsv1());
+		let len = data.as_ref().len();
+		let mut data = data.as_ref().to_vec();
+		receiver.decode(&mut header, &mut data).unwrap();
+		assert!(!header.is_rsv1());
+		assert_eq!(message, &data[..]);
+		len
+	}
+
+	/// Send a frame and return its compressed length.
+	#[cfg(feature = "flate2")]
+	pub(super) fn send_frame<E: Extension>(
+		sender: &mut E,
+		receiver: &mut E,
+		frame: super::FrameContext,
+		data: &[u8],
+	) -> usize {
+		let mut header = Header::new(if frame.is_first() { frame.opcode() } else { OpCode::Continue });
+		let mut storage = Storage::Shared(data);
+		sender.encode_frame(&mut header, &mut storage, frame).unwrap();
+		assert!(header.is_rsv1());
+		let len = storage.as_ref().len();
+		let mut bytes = storage.as_ref().to_vec();
+		receiver.decode_frame(&mut header, &mut bytes, frame).unwrap();
+		assert!(!header.is_rsv1());
+		assert_eq!(data, &bytes[..]);
+		len
+	}
+}
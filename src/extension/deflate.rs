@@ -8,21 +8,28 @@
 
 //! Deflate compression extension mostly conformant with [RFC 7692][rfc7692].
 //!
+//! The compression backend is chosen with one of the following features:
+//!
+//! - `deflate` or `deflate-zlib`: zlib (the default),
+//! - `deflate-zlib-ng`: zlib-ng,
+//! - `deflate-zlib-rs`: zlib-rs, a pure Rust port of zlib,
+//! - `deflate-miniz`: miniz_oxide, a pure Rust implementation.
+//!
+//! All zlib variants support LZ77 window sizes of 8 to 15 bits. With
+//! miniz_oxide, which always uses a window of 15 bits, negotiations
+//! which would limit the window of our compressor are declined.
+//!
 //! [rfc7692]: https://tools.ietf.org/html/rfc7692
 
 use crate::{
 	as_u64,
 	base::{Header, OpCode},
 	connection::Mode,
-	extension::{Extension, Param},
+	extension::{Extension, Fragment, FrameContext, MessageTooLarge, Param},
 	BoxedError, Storage,
 };
-use flate2::{write::DeflateDecoder, Compress, Compression, FlushCompress, Status};
-use std::{
-	convert::TryInto,
-	io::{self, Write},
-	mem,
-};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{convert::TryInto, io, mem};
 
 const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
 const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
@@ -30,19 +37,106 @@ const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
 const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
 const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
 
+/// Can the compression backend limit its LZ77 window size?
+const LIMITED_WINDOW: bool =
+	cfg!(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs"));
+
+/// Number of compressed messages whose sizes are compared in adaptive mode.
+const ADAPTIVE_WINDOW: u32 = 8;
+
+/// Number of messages sent uncompressed in adaptive mode once compression showed no benefit.
+const ADAPTIVE_PAUSE: u32 = 64;
+
 /// The deflate extension type.
 ///
-/// The extension does currently not support max. window bits other than the
-/// default, which is 15 and will ask for no context takeover during handshake.
+/// By default, no context takeover is used in either direction, i.e. every
+/// message is compressed on its own: a client asks for it during handshake
+/// and a server requires it in its response. Use
+/// [`Deflate::set_server_no_context_takeover`] and
+/// [`Deflate::set_client_no_context_takeover`] to allow compressing
+/// messages with the sliding window of previous messages instead.
+///
+/// The compressor and decompressor are created once and reset after
+/// every message without context takeover, so compressing many small
+/// messages does not allocate new streams. In exchange, every connection
+/// keeps them, which takes about 300 KiB with a window of 15 bits.
 #[derive(Debug)]
 pub struct Deflate {
 	mode: Mode,
 	enabled: bool,
-	buffer: Vec<u8>,
 	params: Vec<Param<'static>>,
 	our_max_window_bits: u8,
 	their_max_window_bits: u8,
-	await_last_fragment: bool,
+	/// Configured upper bound of the server's window.
+	server_window_bits: u8,
+	/// Configured upper bound of the client's window.
+	client_window_bits: u8,
+	/// Ask for or offer `server_no_context_takeover`.
+	server_no_context_takeover: bool,
+	/// Ask for or offer `client_no_context_takeover`.
+	client_no_context_takeover: bool,
+	/// Reset our compressor after every message.
+	our_no_context_takeover: bool,
+	/// The remote end resets its compressor after every message.
+	their_no_context_takeover: bool,
+	/// Compressor and decompressor state kept between messages.
+	streams: Streams,
+	level: Compression,
+	/// Messages smaller than this are sent uncompressed.
+	min_compress_size: usize,
+	adaptive: Option<Adaptive>,
+	/// The maximum message size of the connection.
+	max_message_size: usize,
+	/// The maximum size of a decompressed message.
+	max_decompressed_size: usize,
+	/// Uncompressed and compressed size of the fragmented message being sent,
+	/// unless it is sent uncompressed.
+	fragmented: Option<(usize, usize)>,
+	/// Number of bytes decompressed so far of the fragmented message being
+	/// received, unless it is uncompressed.
+	inflating: Option<usize>,
+}
+
+/// Compression statistics of the adaptive mode, cf. [`Deflate::set_adaptive`].
+#[derive(Debug, Default)]
+struct Adaptive {
+	/// Number of messages compressed since the last evaluation.
+	messages: u32,
+	/// Total size of these messages.
+	uncompressed: u64,
+	/// Total size of these messages after compression.
+	compressed: u64,
+	/// Number of messages still to send uncompressed.
+	pause: u32,
+}
+
+impl Adaptive {
+	/// Should the next message be compressed?
+	fn should_compress(&mut self) -> bool {
+		if self.pause > 0 {
+			self.pause -= 1;
+			return false;
+		}
+		true
+	}
+
+	/// Record the size of a compressed message and pause compression if
+	/// it did not save at least 10% over the last messages.
+	fn record(&mut self, uncompressed: usize, compressed: usize) {
+		self.messages += 1;
+		self.uncompressed += as_u64(uncompressed);
+		self.compressed += as_u64(compressed);
+		if self.messages < ADAPTIVE_WINDOW {
+			return;
+		}
+		if self.compressed * 10 > self.uncompressed * 9 {
+			log::debug!("deflate: no benefit from compression, pausing for {} messages", ADAPTIVE_PAUSE);
+			self.pause = ADAPTIVE_PAUSE
+		}
+		self.messages = 0;
+		self.uncompressed = 0;
+		self.compressed = 0;
+	}
 }
 
 impl Deflate {
@@ -51,37 +145,149 @@ impl Deflate {
 		let params = match mode {
 			Mode::Server => Vec::new(),
 			Mode::Client => {
-				let mut params = Vec::new();
-				params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER));
-				params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER));
-				params.push(Param::new(CLIENT_MAX_WINDOW_BITS));
+				let mut params = vec![Param::new(SERVER_NO_CONTEXT_TAKEOVER), Param::new(CLIENT_NO_CONTEXT_TAKEOVER)];
+				if LIMITED_WINDOW {
+					params.push(Param::new(CLIENT_MAX_WINDOW_BITS))
+				}
 				params
 			}
 		};
 		Deflate {
 			mode,
 			enabled: false,
-			buffer: Vec::new(),
 			params,
 			our_max_window_bits: 15,
 			their_max_window_bits: 15,
-			await_last_fragment: false,
+			server_window_bits: 15,
+			client_window_bits: 15,
+			server_no_context_takeover: true,
+			client_no_context_takeover: true,
+			our_no_context_takeover: true,
+			their_no_context_takeover: true,
+			streams: Streams::new(),
+			level: Compression::fast(),
+			min_compress_size: 0,
+			adaptive: None,
+			max_message_size: usize::MAX,
+			max_decompressed_size: usize::MAX,
+			fragmented: None,
+			inflating: None,
+		}
+	}
+
+	/// Set the compression level.
+	///
+	/// The value must be within 0 ..= 9, where 1 is the fastest and 9 the
+	/// best compression. 0 means no compression, i.e. payload data is only
+	/// framed as deflate blocks. The default is 1.
+	pub fn set_compression_level(&mut self, level: u32) -> &mut Self {
+		assert!(level <= 9, "compression level has to be within 0 ..= 9");
+		self.level = Compression::new(level);
+		self.streams.clear_compressor();
+		self
+	}
+
+	/// Send messages smaller than `size` bytes uncompressed.
+	///
+	/// Compressing small messages often makes them bigger. By default,
+	/// all non-empty messages are compressed. Messages sent in fragments
+	/// are always compressed, since their size is not known in advance.
+	pub fn set_min_compress_size(&mut self, size: usize) -> &mut Self {
+		self.min_compress_size = size;
+		self
+	}
+
+	/// Enable or disable adaptive compression.
+	///
+	/// In adaptive mode, the sizes of compressed messages are tracked. If
+	/// compression did not save at least 10% over the last 8 compressed
+	/// messages, the next 64 messages are sent uncompressed before
+	/// compression is tried again. This is disabled by default.
+	pub fn set_adaptive(&mut self, adaptive: bool) -> &mut Self {
+		self.adaptive = if adaptive { Some(Adaptive::default()) } else { None };
+		self
+	}
+
+	/// Set the maximum size of a decompressed message.
+	///
+	/// Decompression stops with an error as soon as the output exceeds
+	/// this size or the maximum message size of the connection, whichever
+	/// is smaller. The connection is then closed with status code 1009
+	/// (message too big). By default only the connection limit applies.
+	pub fn set_max_decompressed_size(&mut self, max: usize) -> &mut Self {
+		self.max_decompressed_size = max;
+		self
+	}
+
+	/// Forbid or allow the server to use context takeover.
+	///
+	/// In client mode this determines whether "server_no_context_takeover" is
+	/// included in the offer (by default it is). If so, the server must
+	/// confirm it. In server mode, "server_no_context_takeover" is always
+	/// included in the response if set (by default it is), otherwise
+	/// only if the client asked for it.
+	///
+	/// Without context takeover, every message is compressed on its own.
+	/// With it, the sliding window of previous messages is used, which
+	/// compresses similar messages better but keeps the compressor state
+	/// between messages.
+	pub fn set_server_no_context_takeover(&mut self, value: bool) {
+		self.server_no_context_takeover = value;
+		if self.mode == Mode::Client {
+			self.set_flag_param(SERVER_NO_CONTEXT_TAKEOVER, value)
+		}
+	}
+
+	/// Forbid or allow the client to use context takeover.
+	///
+	/// In client mode this determines whether "client_no_context_takeover" is
+	/// included in the offer (by default it is), which commits the client to
+	/// not use context takeover. In server mode, "client_no_context_takeover"
+	/// is always included in the response if set (by default it is),
+	/// otherwise only if the client offered it.
+	///
+	/// Cf. [`Deflate::set_server_no_context_takeover`].
+	pub fn set_client_no_context_takeover(&mut self, value: bool) {
+		self.client_no_context_takeover = value;
+		if self.mode == Mode::Client {
+			self.set_flag_param(CLIENT_NO_CONTEXT_TAKEOVER, value)
+		}
+	}
+
+	/// Add or remove a parameter without value.
+	fn set_flag_param(&mut self, name: &'static str, value: bool) {
+		let present = self.params.iter().any(|p| p.name() == name);
+		if value && !present {
+			self.params.push(Param::new(name))
+		} else if !value {
+			self.params.retain(|p| p.name() != name)
 		}
 	}
 
 	/// Set the server's max. window bits.
 	///
-	/// The value must be within 9 ..= 15.
-	/// The extension must be in client mode.
+	/// The value must be within 8 ..= 15, or 15 with the `deflate-miniz`
+	/// backend in server mode.
 	///
-	/// By including this parameter, a client limits the LZ77 sliding window
-	/// size that the server will use to compress messages. A server accepts
-	/// by including the "server_max_window_bits" extension parameter in the
-	/// response with the same or smaller value as the offer.
+	/// In client mode, this parameter is included in the offer and limits
+	/// the LZ77 sliding window size that the server will use to compress
+	/// messages. A server accepts by including the "server_max_window_bits"
+	/// extension parameter in the response with the same or smaller value
+	/// as the offer.
+	///
+	/// In server mode, this limits the server's own window size. The
+	/// response includes "server_max_window_bits" with the smaller of this
+	/// value and the one offered by the client, whether the client included
+	/// the parameter in its offer or not.
 	pub fn set_max_server_window_bits(&mut self, max: u8) {
-		assert!(self.mode == Mode::Client, "setting max. server window bits requires client mode");
-		assert!(max > 8 && max <= 15, "max. server window bits have to be within 9 ..= 15");
-		self.their_max_window_bits = max; // upper bound of the server's window
+		assert!((8..=15).contains(&max), "max. server window bits have to be within 8 ..= 15");
+		if self.mode == Mode::Server {
+			assert!(LIMITED_WINDOW || max == 15, "max. server window bits have to be 15 with this backend");
+		}
+		self.server_window_bits = max;
+		if self.mode == Mode::Server {
+			return;
+		}
 		let mut p = Param::new(SERVER_MAX_WINDOW_BITS);
 		p.set_value(Some(max.to_string()));
 		self.params.push(p)
@@ -89,10 +295,15 @@ impl Deflate {
 
 	/// Set the client's max. window bits.
 	///
-	/// The value must be within 9 ..= 15.
-	/// The extension must be in client mode.
+	/// The value must be within 8 ..= 15, or 15 with the `deflate-miniz`
+	/// backend in client mode, as it can not limit its window size.
+	///
+	/// In server mode, the response includes "client_max_window_bits" with
+	/// the smaller of this value and the one offered by the client. If the
+	/// client did not include the parameter in its offer, it does not
+	/// support it and its window size can not be limited.
 	///
-	/// The parameter informs the server that even if it doesn't include the
+	/// In client mode, the parameter informs the server that even if it doesn't include the
 	/// "client_max_window_bits" extension parameter in the response with a
 	/// value greater than the one in the negotiation offer or if it doesn't
 	/// include the extension parameter at all, the client is not going to
@@ -100,9 +311,13 @@ impl Deflate {
 	/// The server may also respond with a smaller value which allows the client
 	/// to reduce its sliding window even more.
 	pub fn set_max_client_window_bits(&mut self, max: u8) {
-		assert!(self.mode == Mode::Client, "setting max. client window bits requires client mode");
-		assert!(max > 8 && max <= 15, "max. client window bits have to be within 9 ..= 15");
-		self.our_max_window_bits = max; // upper bound of the client's window
+		assert!((8..=15).contains(&max), "max. client window bits have to be within 8 ..= 15");
+		if self.mode == Mode::Server {
+			self.client_window_bits = max;
+			return;
+		}
+		assert!(LIMITED_WINDOW || max == 15, "max. client window bits have to be 15 with this backend");
+		self.client_window_bits = max;
 		if let Some(p) = self.params.iter_mut().find(|p| p.name() == CLIENT_MAX_WINDOW_BITS) {
 			p.set_value(Some(max.to_string()));
 		} else {
@@ -114,7 +329,7 @@ impl Deflate {
 
 	fn set_their_max_window_bits(&mut self, p: &Param, expected: Option<u8>) -> Result<(), ()> {
 		if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-			if v < 8 || v > 15 {
+			if !(8..=15).contains(&v) {
 				log::debug!("invalid {}: {} (expected range: 8 ..= 15)", p.name(), v);
 				return Err(());
 			}
@@ -124,7 +339,32 @@ impl Deflate {
 					return Err(());
 				}
 			}
-			self.their_max_window_bits = std::cmp::max(9, v);
+			self.their_max_window_bits = v;
+		}
+		Ok(())
+	}
+
+	/// Compress `data` and return the size of the output.
+	///
+	/// If `last` is set, the message is complete and all pending output is
+	/// flushed, otherwise the compressor may hold back some of its output.
+	fn deflate(&mut self, data: &[u8], last: bool) -> Result<usize, BoxedError> {
+		let n = self.streams.deflate(data, self.level, self.our_max_window_bits, last)?;
+		if last && self.our_no_context_takeover {
+			self.streams.reset_compressor()
+		}
+		Ok(n)
+	}
+
+	/// Decompress `data` and replace it with the output.
+	///
+	/// `decompressed` is the number of bytes of this message decompressed
+	/// before. If `last` is set, the message is complete.
+	fn inflate(&mut self, data: &mut Vec<u8>, decompressed: usize, last: bool) -> Result<(), BoxedError> {
+		let maximum = std::cmp::min(self.max_message_size, self.max_decompressed_size);
+		self.streams.inflate(data, last, maximum.saturating_sub(decompressed), maximum)?;
+		if last && self.their_no_context_takeover {
+			self.streams.reset_decompressor()
 		}
 		Ok(())
 	}
@@ -139,72 +379,101 @@ impl Extension for Deflate {
 		self.enabled
 	}
 
-	fn params(&self) -> &[Param] {
+	fn params(&self) -> &[Param<'_>] {
 		&self.params
 	}
 
 	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+		// Nothing of a previous negotiation or connection is kept.
+		self.enabled = false;
+		self.streams.clear();
+		self.fragmented = None;
+		self.inflating = None;
+		if let Some(adaptive) = &mut self.adaptive {
+			*adaptive = Adaptive::default()
+		}
 		match self.mode {
 			Mode::Server => {
+				// Every offer is considered on its own.
 				self.params.clear();
+				self.our_max_window_bits = self.server_window_bits;
+				self.their_max_window_bits = 15;
+				self.our_no_context_takeover = self.server_no_context_takeover;
+				self.their_no_context_takeover = self.client_no_context_takeover;
+				let mut client_window_bits_offered = false;
+				let mut server_window_bits_offered = false;
 				for p in params {
 					log::trace!("configure server with: {}", p);
 					match p.name() {
 						CLIENT_MAX_WINDOW_BITS => {
-							if self.set_their_max_window_bits(&p, None).is_err() {
-								// we just accept the client's offer as is => no need to reply
+							if self.set_their_max_window_bits(p, None).is_err() {
 								return Ok(());
 							}
+							client_window_bits_offered = true
 						}
 						SERVER_MAX_WINDOW_BITS => {
 							if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-								// The RFC allows 8 to 15 bits, but due to zlib limitations we
-								// only support 9 to 15.
-								if v < 9 || v > 15 {
+								if !Streams::supports_window_bits(v) {
 									log::debug!("unacceptable server_max_window_bits: {}", v);
 									return Ok(());
 								}
-								let mut x = Param::new(SERVER_MAX_WINDOW_BITS);
-								x.set_value(Some(v.to_string()));
-								self.params.push(x);
-								self.our_max_window_bits = v;
+								self.our_max_window_bits = std::cmp::min(self.our_max_window_bits, v);
+								server_window_bits_offered = true
 							} else {
 								log::debug!("invalid server_max_window_bits: {:?}", p.value());
 								return Ok(());
 							}
 						}
-						CLIENT_NO_CONTEXT_TAKEOVER => self.params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER)),
-						SERVER_NO_CONTEXT_TAKEOVER => self.params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER)),
+						CLIENT_NO_CONTEXT_TAKEOVER => self.their_no_context_takeover = true,
+						SERVER_NO_CONTEXT_TAKEOVER => self.our_no_context_takeover = true,
 						_ => {
 							log::debug!("{}: unknown parameter: {}", self.name(), p.name());
 							return Ok(());
 						}
 					}
 				}
+				// Respond with the strictest parameters allowed, cf. RFC 7692, 7.1.2.
+				if server_window_bits_offered || self.our_max_window_bits < 15 {
+					let mut x = Param::new(SERVER_MAX_WINDOW_BITS);
+					x.set_value(Some(self.our_max_window_bits.to_string()));
+					self.params.push(x)
+				}
+				if client_window_bits_offered && self.client_window_bits < self.their_max_window_bits {
+					self.their_max_window_bits = self.client_window_bits;
+					let mut x = Param::new(CLIENT_MAX_WINDOW_BITS);
+					x.set_value(Some(self.client_window_bits.to_string()));
+					self.params.push(x)
+				}
+				if self.their_no_context_takeover {
+					self.params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER))
+				}
+				if self.our_no_context_takeover {
+					self.params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER))
+				}
 			}
 			Mode::Client => {
 				let mut server_no_context_takeover = false;
+				self.our_max_window_bits = self.client_window_bits;
+				self.their_max_window_bits = self.server_window_bits;
+				self.our_no_context_takeover = self.client_no_context_takeover;
 				for p in params {
 					log::trace!("configure client with: {}", p);
 					match p.name() {
 						SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
-						CLIENT_NO_CONTEXT_TAKEOVER => {} // must be supported
+						CLIENT_NO_CONTEXT_TAKEOVER => self.our_no_context_takeover = true,
 						SERVER_MAX_WINDOW_BITS => {
 							let expected = Some(self.their_max_window_bits);
-							if self.set_their_max_window_bits(&p, expected).is_err() {
+							if self.set_their_max_window_bits(p, expected).is_err() {
 								return Ok(());
 							}
 						}
 						CLIENT_MAX_WINDOW_BITS => {
 							if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-								if v < 8 || v > 15 {
+								if !Streams::supports_window_bits(v) {
 									log::debug!("unacceptable client_max_window_bits: {}", v);
 									return Ok(());
 								}
-								use std::cmp::{max, min};
-								// Due to zlib limitations we have to use 9 as a lower bound
-								// here, even if the server allowed us to go down to 8 bits.
-								self.our_max_window_bits = min(self.our_max_window_bits, max(9, v));
+								self.our_max_window_bits = std::cmp::min(self.our_max_window_bits, v);
 							}
 						}
 						_ => {
@@ -213,20 +482,29 @@ impl Extension for Deflate {
 						}
 					}
 				}
-				if !server_no_context_takeover {
+				if self.server_no_context_takeover && !server_no_context_takeover {
 					log::debug!("{}: server did not confirm no context takeover", self.name());
 					return Ok(());
 				}
+				self.their_no_context_takeover = server_no_context_takeover;
 			}
 		}
 		self.enabled = true;
 		Ok(())
 	}
 
+	fn encodes_fragments(&self) -> bool {
+		true
+	}
+
 	fn reserved_bits(&self) -> (bool, bool, bool) {
 		(true, false, false)
 	}
 
+	fn set_max_message_size(&mut self, max: usize) {
+		self.max_message_size = max
+	}
+
 	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
 		if data.is_empty() {
 			return Ok(());
@@ -234,15 +512,6 @@ impl Extension for Deflate {
 
 		match header.opcode() {
 			OpCode::Binary | OpCode::Text if header.is_rsv1() => {
-				if !header.is_fin() {
-					self.await_last_fragment = true;
-					log::trace!("deflate: not decoding {}; awaiting last fragment", header);
-					return Ok(());
-				}
-				log::trace!("deflate: decoding {}", header)
-			}
-			OpCode::Continue if header.is_fin() && self.await_last_fragment => {
-				self.await_last_fragment = false;
 				log::trace!("deflate: decoding {}", header)
 			}
 			_ => {
@@ -251,14 +520,7 @@ impl Extension for Deflate {
 			}
 		}
 
-		// Restore LEN and NLEN:
-		data.extend_from_slice(&[0, 0, 0xFF, 0xFF]); // cf. RFC 7692, 7.2.2
-
-		self.buffer.clear();
-		let mut decoder = DeflateDecoder::new(&mut self.buffer);
-		decoder.write_all(&data)?;
-		decoder.finish()?;
-		mem::swap(data, &mut self.buffer);
+		self.inflate(data, 0, true)?;
 
 		header.set_rsv1(false);
 		header.set_payload_len(data.len());
@@ -266,6 +528,36 @@ impl Extension for Deflate {
 		Ok(())
 	}
 
+	fn decode_frame(&mut self, header: &mut Header, data: &mut Vec<u8>, frame: FrameContext) -> Result<(), BoxedError> {
+		// Complete messages are decompressed by `decode`.
+		if frame.fragment() == Fragment::Unfragmented || !matches!(frame.opcode(), OpCode::Binary | OpCode::Text) {
+			return Ok(());
+		}
+
+		// Fragmented messages are decompressed fragment by fragment as they arrive.
+		// Clearing RSV1 of the initial frame makes `decode` skip the complete message.
+		if frame.is_first() {
+			self.inflating = if header.is_rsv1() { Some(0) } else { None };
+			header.set_rsv1(false);
+		}
+
+		let decompressed = match self.inflating.take() {
+			Some(n) => n,
+			None => {
+				log::trace!("deflate: not decoding {}", header);
+				return Ok(());
+			}
+		};
+
+		log::trace!("deflate: decoding {}", header);
+		self.inflate(data, decompressed, frame.is_last())?;
+		if !frame.is_last() {
+			self.inflating = Some(decompressed + data.len())
+		}
+		header.set_payload_len(data.len());
+		Ok(())
+	}
+
 	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
 		if data.as_ref().is_empty() {
 			return Ok(());
@@ -278,28 +570,154 @@ impl Extension for Deflate {
 			return Ok(());
 		}
 
+		if data.as_ref().len() < self.min_compress_size {
+			log::trace!("deflate: not compressing {} bytes", data.as_ref().len());
+			return Ok(());
+		}
+
+		if let Some(adaptive) = &mut self.adaptive {
+			if !adaptive.should_compress() {
+				log::trace!("deflate: compression paused");
+				return Ok(());
+			}
+		}
+
+		let compressed = self.deflate(data.as_ref(), true)?;
+
+		if let Some(adaptive) = &mut self.adaptive {
+			adaptive.record(data.as_ref().len(), compressed)
+		}
+
+		self.streams.take_buffer(data);
+		header.set_rsv1(true);
+		header.set_payload_len(data.as_ref().len());
+		Ok(())
+	}
+
+	fn encode_frame(&mut self, header: &mut Header, data: &mut Storage, frame: FrameContext) -> Result<(), BoxedError> {
+		// Complete messages are compressed by `encode`.
+		if frame.fragment() == Fragment::Unfragmented || !matches!(frame.opcode(), OpCode::Binary | OpCode::Text) {
+			return Ok(());
+		}
+
+		if frame.is_first() {
+			let compress = self.adaptive.as_mut().map_or(true, Adaptive::should_compress);
+			self.fragmented = if compress { Some((0, 0)) } else { None }
+		}
+
+		let (mut uncompressed, mut compressed) = match self.fragmented.take() {
+			Some(sizes) => sizes,
+			None => {
+				log::trace!("deflate: not encoding {}", header);
+				return Ok(());
+			}
+		};
+
+		log::trace!("deflate: encoding {}", header);
+		compressed += self.deflate(data.as_ref(), frame.is_last())?;
+		uncompressed += data.as_ref().len();
+
+		if !frame.is_last() {
+			self.fragmented = Some((uncompressed, compressed))
+		} else if let Some(adaptive) = &mut self.adaptive {
+			adaptive.record(uncompressed, compressed)
+		}
+
+		self.streams.take_buffer(data);
+		if frame.is_first() {
+			header.set_rsv1(true); // Only the initial frame has RSV1 set; cf. RFC 7692, 6.1
+		}
+		header.set_payload_len(data.as_ref().len());
+		Ok(())
+	}
+}
+
+/// Raw deflate compressor and decompressor shared by the deflate extensions.
+///
+/// Both are created on first use and kept until they are cleared, so that
+/// their state can be carried over from one message or frame to the next.
+#[derive(Debug)]
+pub(super) struct Streams {
+	compress: Option<Compress>,
+	decompress: Option<Decompress>,
+	/// Output of the last compression or decompression.
+	buffer: Vec<u8>,
+}
+
+impl Streams {
+	pub(super) fn new() -> Self {
+		Streams { compress: None, decompress: None, buffer: Vec::new() }
+	}
+
+	/// Can a compressor with a window of `bits` be created?
+	pub(super) fn supports_window_bits(bits: u8) -> bool {
+		(8..=15).contains(&bits) && (LIMITED_WINDOW || bits == 15)
+	}
+
+	/// Drop the compressor and decompressor.
+	pub(super) fn clear(&mut self) {
+		self.compress = None;
+		self.decompress = None
+	}
+
+	/// Drop the compressor, e.g. to create it with another compression level.
+	pub(super) fn clear_compressor(&mut self) {
+		self.compress = None
+	}
+
+	/// Forget the sliding window of the compressor.
+	pub(super) fn reset_compressor(&mut self) {
+		if let Some(c) = &mut self.compress {
+			c.reset()
+		}
+	}
+
+	/// Forget the sliding window of the decompressor.
+	pub(super) fn reset_decompressor(&mut self) {
+		if let Some(d) = &mut self.decompress {
+			d.reset(false)
+		}
+	}
+
+	/// Compress `data` into `self.buffer` and return the size of the output.
+	///
+	/// If `flush` is set, all pending output is flushed and the trailing
+	/// empty deflate block removed, otherwise the compressor may hold back
+	/// some of its output.
+	pub(super) fn deflate(
+		&mut self,
+		data: &[u8],
+		level: Compression,
+		window_bits: u8,
+		flush: bool,
+	) -> Result<usize, BoxedError> {
 		self.buffer.clear();
-		self.buffer.reserve(data.as_ref().len());
+		self.buffer.reserve(data.len());
 
-		let mut encoder = Compress::new_with_window_bits(Compression::fast(), false, self.our_max_window_bits);
+		let encoder = self.compress.get_or_insert_with(|| compressor(level, window_bits));
+		let start = encoder.total_in();
 
 		// Compress all input bytes.
-		while encoder.total_in() < as_u64(data.as_ref().len()) {
-			let i: usize = encoder.total_in().try_into()?;
-			match encoder.compress_vec(&data.as_ref()[i..], &mut self.buffer, FlushCompress::None)? {
+		while encoder.total_in() - start < as_u64(data.len()) {
+			let i: usize = (encoder.total_in() - start).try_into()?;
+			match encoder.compress_vec(&data[i..], &mut self.buffer, FlushCompress::None)? {
 				Status::BufError => self.buffer.reserve(4096),
 				Status::Ok => continue,
 				Status::StreamEnd => break,
 			}
 		}
 
-		// We need to append an empty deflate block if not there yet (RFC 7692, 7.2.1).
-		while !self.buffer.ends_with(&[0, 0, 0xFF, 0xFF]) {
-			self.buffer.reserve(5); // Make sure there is room for the trailing end bytes.
+		if !flush {
+			return Ok(self.buffer.len());
+		}
+
+		// Flush all pending output, which appends an empty deflate block (RFC 7692, 7.2.1).
+		// The flush is complete once the encoder does not use up all available space.
+		loop {
+			self.buffer.reserve(64);
 			match encoder.compress_vec(&[], &mut self.buffer, FlushCompress::Sync)? {
-				Status::Ok => continue,
-				Status::BufError => continue, // more capacity is reserved above
-				Status::StreamEnd => break,
+				Status::Ok | Status::BufError if self.buffer.len() == self.buffer.capacity() => continue,
+				Status::Ok | Status::BufError | Status::StreamEnd => break,
 			}
 		}
 
@@ -310,14 +728,414 @@ impl Extension for Deflate {
 		}
 
 		self.buffer.truncate(self.buffer.len() - 4); // Remove 00 00 FF FF; cf. RFC 7692, 7.2.1
+		Ok(self.buffer.len())
+	}
+
+	/// Decompress `data` and replace it with the output.
+	///
+	/// If `flushed` is set, `data` ends with a flush whose empty deflate
+	/// block has been removed. At most `available` bytes are decompressed,
+	/// otherwise [`MessageTooLarge`] is returned with `maximum` as the limit
+	/// of the whole message. On error, the decompressor is reset.
+	pub(super) fn inflate(
+		&mut self,
+		data: &mut Vec<u8>,
+		flushed: bool,
+		available: usize,
+		maximum: usize,
+	) -> Result<(), BoxedError> {
+		if flushed {
+			// Restore LEN and NLEN:
+			data.extend_from_slice(&[0, 0, 0xFF, 0xFF]); // cf. RFC 7692, 7.2.2
+		}
+		if let Err(e) = self.decompress(data, available, maximum) {
+			// Start over with the next message.
+			self.reset_decompressor();
+			self.buffer.clear();
+			return Err(e);
+		}
+		mem::swap(data, &mut self.buffer);
+		Ok(())
+	}
 
+	/// Decompress `data` into `self.buffer`, cf. [`Streams::inflate`].
+	fn decompress(&mut self, data: &[u8], available: usize, maximum: usize) -> Result<(), BoxedError> {
+		self.buffer.clear();
+		self.buffer.reserve(std::cmp::min(data.len().saturating_mul(2), available.saturating_add(1)));
+
+		let decoder = self.decompress.get_or_insert_with(|| Decompress::new(false));
+		let start = decoder.total_in();
+
+		// Decompress all input bytes until all output has been produced.
+		loop {
+			let i: usize = (decoder.total_in() - start).try_into()?;
+			if i == data.len() && self.buffer.len() < self.buffer.capacity() {
+				break;
+			}
+			if self.buffer.len() == self.buffer.capacity() {
+				// Grow no further than one byte beyond the limit, which suffices to detect a violation.
+				let allowance = available.saturating_add(1).saturating_sub(self.buffer.len());
+				self.buffer.reserve_exact(std::cmp::min(4096, allowance))
+			}
+			let status = decoder.decompress_vec(&data[i..], &mut self.buffer, FlushDecompress::Sync)?;
+			if self.buffer.len() > available {
+				return Err(MessageTooLarge { current: maximum.saturating_add(1), maximum }.into());
+			}
+			match status {
+				Status::Ok | Status::BufError => continue,
+				Status::StreamEnd => break,
+			}
+		}
+		Ok(())
+	}
+
+	/// Replace `data` with the compressed output in `self.buffer`.
+	pub(super) fn take_buffer(&mut self, data: &mut Storage) {
 		if let Storage::Owned(d) = data {
 			mem::swap(d, &mut self.buffer)
 		} else {
 			*data = Storage::Owned(mem::take(&mut self.buffer))
 		}
+	}
+}
+
+/// Create a raw deflate compressor with the given LZ77 window size.
+#[cfg(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs"))]
+fn compressor(level: Compression, window_bits: u8) -> Compress {
+	// zlib does not support 8 bits for raw deflate streams. With 9 bits,
+	// match distances are at most 512 - 262 = 250 bytes, so the output can
+	// be decompressed with a window of 8 bits.
+	Compress::new_with_window_bits(level, false, std::cmp::max(9, window_bits))
+}
+
+/// Create a raw deflate compressor with a window size of 15 bits.
+#[cfg(not(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs")))]
+fn compressor(level: Compression, _window_bits: u8) -> Compress {
+	Compress::new(level, false)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Deflate, ADAPTIVE_PAUSE};
+	use crate::{
+		base::{Header, OpCode},
+		connection::Mode,
+		extension::{
+			tests::{negotiate, send},
+			Extension, Fragment, FrameContext, MessageTooLarge, Param,
+		},
+		Storage,
+	};
+
+	/// Send the same message twice and return the compressed lengths.
+	fn send_twice(sender: &mut Deflate, receiver: &mut Deflate) -> (usize, usize) {
+		let message = br#"{"jsonrpc":"2.0","method":"subscribe","params":["newHeads"]}"#;
+		(send(sender, receiver, message), send(sender, receiver, message))
+	}
+
+	#[test]
+	fn min_size_and_adaptive_mode() {
+		let (mut client, _) = negotiate(Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		client.set_min_compress_size(8).set_adaptive(true);
+
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Owned(b"hello".to_vec());
+		client.encode(&mut header, &mut data).unwrap();
+		assert!(!header.is_rsv1());
+		assert_eq!(b"hello", data.as_ref());
+
+		// Incompressible data pauses compression after 8 messages.
+		let mut state = 1_u32;
+		let mut random = || {
+			(0..256)
+				.map(|_| {
+					state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+					(state >> 24) as u8
+				})
+				.collect::<Vec<_>>()
+		};
+		for i in 0..8 + ADAPTIVE_PAUSE + 1 {
+			let mut header = Header::new(OpCode::Binary);
+			client.encode(&mut header, &mut Storage::Owned(random())).unwrap();
+			assert_eq!(i < 8 || i == 8 + ADAPTIVE_PAUSE, header.is_rsv1());
+		}
+	}
+
+	#[test]
+	fn context_takeover() {
+		let (mut client, mut server) = negotiate(Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		let (first, second) = send_twice(&mut client, &mut server);
+		assert_eq!(first, second);
+
+		let mut client = Deflate::new(Mode::Client);
+		client.set_server_no_context_takeover(false);
+		client.set_client_no_context_takeover(false);
+		let mut server = Deflate::new(Mode::Server);
+		server.set_server_no_context_takeover(false);
+		server.set_client_no_context_takeover(false);
+		let (mut client, mut server) = negotiate(client, server);
+		assert!(server.params().is_empty());
+		let (first, second) = send_twice(&mut client, &mut server);
+		assert!(second < first / 2);
+		let (first, second) = send_twice(&mut server, &mut client);
+		assert!(second < first / 2);
+
+		// By default, the server refuses to use context takeover.
+		let mut client = Deflate::new(Mode::Client);
+		client.set_server_no_context_takeover(false);
+		client.set_client_no_context_takeover(false);
+		let (mut client, mut server) = negotiate(client, Deflate::new(Mode::Server));
+		let (first, second) = send_twice(&mut server, &mut client);
+		assert_eq!(first, second);
+		let (first, second) = send_twice(&mut client, &mut server);
+		assert_eq!(first, second);
+
+		// A reused extension starts over with every negotiation.
+		let mut client = Deflate::new(Mode::Client);
+		client.set_client_no_context_takeover(false);
+		let mut server = Deflate::new(Mode::Server);
+		server.set_client_no_context_takeover(false);
+		let (mut client, mut server) = negotiate(client, server);
+		send_twice(&mut client, &mut server);
+		let (mut client, mut server) = negotiate(client, server);
+		let (first, second) = send_twice(&mut client, &mut server);
+		assert!(second < first / 2);
+		let mut fresh = Deflate::new(Mode::Client);
+		fresh.set_client_no_context_takeover(false);
+		let (mut fresh, mut server) = negotiate(fresh, server);
+		assert_eq!(first, send_twice(&mut fresh, &mut server).0);
+	}
+
+	#[test]
+	fn decompressed_size_is_limited() {
+		let (mut client, mut server) = negotiate(Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Owned(vec![0; 1024 * 1024]);
+		client.encode(&mut header, &mut data).unwrap();
+		assert!(data.as_ref().len() < 64 * 1024);
+
+		server.set_max_message_size(64 * 1024);
+		let e = server.decode(&mut header.clone(), &mut data.as_ref().to_vec()).unwrap_err();
+		let e = e.downcast_ref::<MessageTooLarge>().unwrap();
+		assert_eq!(64 * 1024, e.maximum);
+		assert!(e.current > e.maximum);
+		assert!(server.streams.buffer.capacity() <= 64 * 1024 + 1);
+
+		server.set_max_message_size(usize::MAX);
+		server.set_max_decompressed_size(1024 * 1024);
+		let mut payload = data.as_ref().to_vec();
+		server.decode(&mut header, &mut payload).unwrap();
+		assert_eq!(1024 * 1024, payload.len());
+	}
+
+	/// Compress a message whose content repeats after 600 bytes.
+	#[cfg(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs"))]
+	fn compress_repeating(sender: &mut Deflate) -> (Header, Vec<u8>) {
+		let mut state = 7_u32;
+		let block = (0..600)
+			.map(|_| {
+				state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+				(state >> 24) as u8
+			})
+			.collect::<Vec<_>>();
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Owned(block.repeat(4));
+		sender.encode(&mut header, &mut data).unwrap();
+		(header, data.as_ref().to_vec())
+	}
+
+	/// Can the data be decompressed with a window of 9 bits?
+	///
+	/// flate2 does not support decompressing with 8 bits, but with a window
+	/// of 9 bits zlib only produces match distances of at most 250 bytes.
+	#[cfg(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs"))]
+	fn inflates_with_small_window(data: &[u8]) -> bool {
+		let mut decoder = flate2::Decompress::new_with_window_bits(false, 9);
+		// Small output chunks make sure that matches are resolved via the window.
+		let mut chunk = [0; 64];
+		loop {
+			let (i, n) = (decoder.total_in(), decoder.total_out());
+			if decoder.decompress(&data[i as usize..], &mut chunk, flate2::FlushDecompress::Sync).is_err() {
+				return false;
+			}
+			if decoder.total_out() == n {
+				return n == 2400;
+			}
+		}
+	}
+
+	#[cfg(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs"))]
+	#[test]
+	fn eight_bit_windows() {
+		let (mut client, server) = negotiate(Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		client.set_compression_level(9);
+		// zlib-rs does not enforce the window size when decompressing.
+		#[cfg(any(feature = "deflate-zlib", feature = "deflate-zlib-ng"))]
+		assert!(!inflates_with_small_window(&compress_repeating(&mut client).1));
+
+		let mut client = Deflate::new(Mode::Client);
+		client.set_max_server_window_bits(8);
+		client.set_max_client_window_bits(8);
+		let (mut client, mut server) = negotiate(client, server);
+		assert!(server.params().iter().any(|p| p.to_string() == "server_max_window_bits = 8"));
+		let roundtrip = |sender: &mut Deflate, receiver: &mut Deflate| {
+			let (mut header, mut data) = compress_repeating(sender);
+			assert!(inflates_with_small_window(&data));
+			receiver.decode(&mut header, &mut data).unwrap();
+			assert_eq!(2400, data.len());
+		};
+		roundtrip(&mut client, &mut server);
+		roundtrip(&mut server, &mut client);
+
+		// The server may limit the client's window to 8 bits.
+		let mut client = Deflate::new(Mode::Client);
+		let mut response = Param::new("client_max_window_bits");
+		response.set_value(Some("8"));
+		client.configure(&[Param::new("server_no_context_takeover"), response]).unwrap();
+		assert!(client.is_enabled());
+		assert!(inflates_with_small_window(&compress_repeating(&mut client).1));
+	}
+
+	#[cfg(not(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs")))]
+	#[test]
+	fn window_bits_without_zlib() {
+		// Our window can not be limited, so we do not offer to and decline offers which do.
+		let client = Deflate::new(Mode::Client);
+		assert!(client.params().iter().all(|p| p.name() != "client_max_window_bits"));
+		let mut server = Deflate::new(Mode::Server);
+		let mut offer = Param::new("server_max_window_bits");
+		offer.set_value(Some("10"));
+		server.configure(&[offer]).unwrap();
+		assert!(!server.is_enabled());
+
+		// The server's window may still be limited.
+		let mut client = Deflate::new(Mode::Client);
+		client.set_max_server_window_bits(8);
+		let mut response = Param::new("server_max_window_bits");
+		response.set_value(Some("8"));
+		client.configure(&[Param::new("server_no_context_takeover"), response]).unwrap();
+		assert!(client.is_enabled());
+	}
+
+	#[test]
+	fn fragmented_messages() {
+		let (mut client, mut server) = negotiate(Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		let text = b"All work and no play makes Jack a dull boy. ".repeat(20);
+		let fragments = [Fragment::First, Fragment::Continuation, Fragment::Final];
+		for _ in 0..2 {
+			let mut compressed = Vec::new();
+			for (chunk, fragment) in text.chunks(300).zip(fragments) {
+				let mut header = Header::new(OpCode::Continue);
+				let mut data = Storage::Shared(chunk);
+				client.encode_frame(&mut header, &mut data, FrameContext::new(fragment, OpCode::Text)).unwrap();
+				assert_eq!(fragment == Fragment::First, header.is_rsv1());
+				compressed.extend_from_slice(data.as_ref())
+			}
+			assert!(compressed.len() < text.len() / 4);
+			let mut header = Header::new(OpCode::Text);
+			header.set_rsv1(true);
+			server.decode(&mut header, &mut compressed).unwrap();
+			assert_eq!(text, compressed);
+		}
+		let (first, second) = send_twice(&mut client, &mut server);
+		assert_eq!(first, second);
+	}
+
+	#[cfg(any(feature = "deflate-zlib", feature = "deflate-zlib-ng", feature = "deflate-zlib-rs"))]
+	#[test]
+	fn server_policy() {
+		let response = |server: &Deflate| server.params().iter().map(|p| p.to_string()).collect::<Vec<_>>();
+		let mut server = Deflate::new(Mode::Server);
+		server.set_max_server_window_bits(10);
+		server.set_max_client_window_bits(12);
+		server.set_server_no_context_takeover(true);
+		server.set_client_no_context_takeover(true);
+
+		// The client allows context takeover in both directions.
+		let mut client = Deflate::new(Mode::Client);
+		client.set_server_no_context_takeover(false);
+		client.set_client_no_context_takeover(false);
+		let (mut client, mut server) = negotiate(client, server);
+		assert_eq!(
+			vec![
+				"server_max_window_bits = 10",
+				"client_max_window_bits = 12",
+				"client_no_context_takeover",
+				"server_no_context_takeover"
+			],
+			response(&server)
+		);
+		assert_eq!(
+			(12, true, true),
+			(client.our_max_window_bits, client.our_no_context_takeover, client.their_no_context_takeover)
+		);
+		let (first, second) = send_twice(&mut client, &mut server);
+		assert_eq!(first, second);
+		let (first, second) = send_twice(&mut server, &mut client);
+		assert_eq!(first, second);
+
+		// Smaller values offered by the client are used.
+		let mut offer = Param::new("server_max_window_bits");
+		offer.set_value(Some("9"));
+		server.configure(&[offer]).unwrap();
+		assert_eq!(
+			vec!["server_max_window_bits = 9", "client_no_context_takeover", "server_no_context_takeover"],
+			response(&server)
+		);
+
+		// The client does not keep the window size of a previous negotiation.
+		let (client, _) = negotiate(client, Deflate::new(Mode::Server));
+		assert_eq!(15, client.our_max_window_bits);
+	}
+
+	#[test]
+	fn corrupt_messages_are_not_fatal() {
+		let (mut client, mut server) = negotiate(Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		let message = b"All work and no play makes Jack a dull boy.";
+		send(&mut client, &mut server, message);
+		let mut header = Header::new(OpCode::Text);
 		header.set_rsv1(true);
-		header.set_payload_len(data.as_ref().len());
-		Ok(())
+		assert!(server.decode(&mut header, &mut vec![0xff; 8]).is_err());
+		send(&mut client, &mut server, message);
+	}
+
+	#[test]
+	fn fragments_are_inflated_as_they_arrive() {
+		let (mut client, mut server) = negotiate(Deflate::new(Mode::Client), Deflate::new(Mode::Server));
+		let text = (0..1000).map(|i| (i * 7919 % 100_000).to_string()).collect::<Vec<_>>().join(",").into_bytes();
+		let mut header = Header::new(OpCode::Text);
+		let mut data = Storage::Owned(text.clone());
+		client.encode(&mut header, &mut data).unwrap();
+		let compressed = data.as_ref().to_vec();
+		let third = compressed.len() / 3;
+		let frames = [
+			(Fragment::First, &compressed[..third]),
+			(Fragment::Continuation, &compressed[third..2 * third]),
+			(Fragment::Final, &compressed[2 * third..]),
+		];
+
+		let mut message = Vec::new();
+		for (fragment, chunk) in frames {
+			let mut header = Header::new(OpCode::Continue);
+			header.set_rsv1(fragment == Fragment::First);
+			let mut data = chunk.to_vec();
+			server.decode_frame(&mut header, &mut data, FrameContext::new(fragment, OpCode::Text)).unwrap();
+			assert!(!header.is_rsv1());
+			assert!(!data.is_empty());
+			message.extend_from_slice(&data)
+		}
+		assert_eq!(text, message);
+
+		// The maximum size applies to the whole message.
+		server.set_max_message_size(text.len() - 1);
+		let mut results = Vec::new();
+		for (fragment, chunk) in frames {
+			let mut header = Header::new(OpCode::Continue);
+			header.set_rsv1(fragment == Fragment::First);
+			let result =
+				server.decode_frame(&mut header, &mut chunk.to_vec(), FrameContext::new(fragment, OpCode::Text));
+			results.push(result.map_err(|e| e.downcast::<MessageTooLarge>().is_ok()))
+		}
+		assert_eq!(Err(true), results[2]);
 	}
 }
@@ -0,0 +1,327 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Legacy per-frame deflate compression extension as per
+//! [draft-tyoshino-hybi-websocket-perframe-deflate][draft].
+//!
+//! This extension predates `permessage-deflate` and is still spoken by
+//! some older clients under the name `x-webkit-deflate-frame` or
+//! `deflate-frame`. Unlike `permessage-deflate`, every data frame is
+//! compressed on its own and has RSV1 set if it is compressed. New
+//! applications should use [`Deflate`](super::deflate::Deflate) instead.
+//!
+//! The following parameters are negotiated during handshake. If included
+//! in the client's offer, they apply to the server's compressor, if
+//! included in the server's response, they apply to the client's:
+//!
+//! - `max_window_bits`: the maximum LZ77 window size, within 8 ..= 15.
+//! - `no_context_takeover`: compress every frame without the sliding
+//!   window of previous frames.
+//!
+//! [draft]: https://tools.ietf.org/html/draft-tyoshino-hybi-websocket-perframe-deflate-06
+
+use super::deflate::Streams;
+use crate::{
+	base::{Header, OpCode},
+	connection::Mode,
+	extension::{Extension, FrameContext, Param},
+	BoxedError, Storage,
+};
+use flate2::Compression;
+
+const MAX_WINDOW_BITS: &str = "max_window_bits";
+const NO_CONTEXT_TAKEOVER: &str = "no_context_takeover";
+
+/// The per-frame deflate extension type.
+///
+/// The parameters set with [`DeflateFrame::set_max_window_bits`] and
+/// [`DeflateFrame::set_no_context_takeover`] are requested from the remote
+/// end, i.e. they are included in the offer in client mode and in the
+/// response in server mode. By default, none are requested.
+#[derive(Debug)]
+pub struct DeflateFrame {
+	mode: Mode,
+	enabled: bool,
+	prefixed: bool,
+	params: Vec<Param<'static>>,
+	our_max_window_bits: u8,
+	/// Reset our compressor after every frame.
+	our_no_context_takeover: bool,
+	/// Compressor and decompressor state kept between frames.
+	streams: Streams,
+	level: Compression,
+	/// The maximum message size of the connection.
+	max_message_size: usize,
+	/// The maximum size of a decompressed message.
+	max_decompressed_size: usize,
+	/// Number of bytes decompressed so far of the message being received.
+	inflated: usize,
+}
+
+impl DeflateFrame {
+	/// Create a new per-frame deflate extension either on client or server side.
+	pub fn new(mode: Mode) -> Self {
+		DeflateFrame {
+			mode,
+			enabled: false,
+			prefixed: true,
+			params: Vec::new(),
+			our_max_window_bits: 15,
+			our_no_context_takeover: false,
+			streams: Streams::new(),
+			level: Compression::fast(),
+			max_message_size: usize::MAX,
+			max_decompressed_size: usize::MAX,
+			inflated: 0,
+		}
+	}
+
+	/// Use the name `x-webkit-deflate-frame` or `deflate-frame`.
+	///
+	/// By default, the prefixed name `x-webkit-deflate-frame` is used.
+	pub fn set_prefixed(&mut self, prefixed: bool) -> &mut Self {
+		self.prefixed = prefixed;
+		self
+	}
+
+	/// Set the compression level.
+	///
+	/// The value must be within 0 ..= 9, where 1 is the fastest and 9 the
+	/// best compression. The default is 1.
+	pub fn set_compression_level(&mut self, level: u32) -> &mut Self {
+		assert!(level <= 9, "compression level has to be within 0 ..= 9");
+		self.level = Compression::new(level);
+		self.streams.clear_compressor();
+		self
+	}
+
+	/// Set the maximum size of a decompressed message.
+	///
+	/// Cf. [`Deflate::set_max_decompressed_size`](super::deflate::Deflate::set_max_decompressed_size).
+	pub fn set_max_decompressed_size(&mut self, max: usize) -> &mut Self {
+		self.max_decompressed_size = max;
+		self
+	}
+
+	/// Limit the LZ77 window size of the remote end's compressor.
+	///
+	/// The value must be within 8 ..= 15.
+	pub fn set_max_window_bits(&mut self, max: u8) -> &mut Self {
+		assert!((8..=15).contains(&max), "max. window bits have to be within 8 ..= 15");
+		self.params.retain(|p| p.name() != MAX_WINDOW_BITS);
+		let mut p = Param::new(MAX_WINDOW_BITS);
+		p.set_value(Some(max.to_string()));
+		self.params.push(p);
+		self
+	}
+
+	/// Forbid or allow the remote end to use context takeover.
+	pub fn set_no_context_takeover(&mut self, value: bool) -> &mut Self {
+		self.params.retain(|p| p.name() != NO_CONTEXT_TAKEOVER);
+		if value {
+			self.params.push(Param::new(NO_CONTEXT_TAKEOVER))
+		}
+		self
+	}
+
+	/// Compress a complete frame into the output buffer.
+	fn deflate(&mut self, data: &[u8]) -> Result<(), BoxedError> {
+		self.streams.deflate(data, self.level, self.our_max_window_bits, true)?;
+		if self.our_no_context_takeover {
+			self.streams.reset_compressor()
+		}
+		Ok(())
+	}
+
+	/// Decompress a complete frame and replace `data` with the output.
+	fn inflate(&mut self, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+		// The remote end may or may not reset its compressor after every frame,
+		// either way the output can be decompressed without resetting ours.
+		let maximum = std::cmp::min(self.max_message_size, self.max_decompressed_size);
+		self.streams.inflate(data, true, maximum.saturating_sub(self.inflated), maximum)?;
+		self.inflated += data.len();
+		Ok(())
+	}
+}
+
+impl Extension for DeflateFrame {
+	fn name(&self) -> &str {
+		if self.prefixed {
+			"x-webkit-deflate-frame"
+		} else {
+			"deflate-frame"
+		}
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn params(&self) -> &[Param<'_>] {
+		&self.params
+	}
+
+	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+		self.enabled = false;
+		self.streams.clear();
+		self.inflated = 0;
+		// The parameters of the offer or response apply to our compressor.
+		let mut max_window_bits = 15;
+		let mut no_context_takeover = false;
+		for p in params {
+			log::trace!("configure {:?} with: {}", self.mode, p);
+			match p.name() {
+				MAX_WINDOW_BITS => match p.value().map(|s| s.parse::<u8>()) {
+					Some(Ok(v)) if Streams::supports_window_bits(v) => max_window_bits = v,
+					_ => {
+						log::debug!("unacceptable max_window_bits: {:?}", p.value());
+						return Ok(());
+					}
+				},
+				NO_CONTEXT_TAKEOVER => no_context_takeover = true,
+				_ => {
+					log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+					return Ok(());
+				}
+			}
+		}
+		self.our_max_window_bits = max_window_bits;
+		self.our_no_context_takeover = no_context_takeover;
+		self.enabled = true;
+		Ok(())
+	}
+
+	fn encodes_fragments(&self) -> bool {
+		true
+	}
+
+	fn reserved_bits(&self) -> (bool, bool, bool) {
+		(true, false, false)
+	}
+
+	fn set_max_message_size(&mut self, max: usize) {
+		self.max_message_size = max
+	}
+
+	fn decode_frame(&mut self, header: &mut Header, data: &mut Vec<u8>, frame: FrameContext) -> Result<(), BoxedError> {
+		if frame.is_first() {
+			self.inflated = 0
+		}
+
+		match frame.opcode() {
+			OpCode::Binary | OpCode::Text if header.is_rsv1() => {
+				log::trace!("deflate-frame: decoding {}", header)
+			}
+			_ => {
+				log::trace!("deflate-frame: not decoding {}", header);
+				return Ok(());
+			}
+		}
+
+		self.inflate(data)?;
+		header.set_rsv1(false);
+		header.set_payload_len(data.len());
+		Ok(())
+	}
+
+	fn encode_frame(&mut self, header: &mut Header, data: &mut Storage, frame: FrameContext) -> Result<(), BoxedError> {
+		if data.as_ref().is_empty() || !matches!(frame.opcode(), OpCode::Binary | OpCode::Text) {
+			log::trace!("deflate-frame: not encoding {}", header);
+			return Ok(());
+		}
+
+		log::trace!("deflate-frame: encoding {}", header);
+		self.deflate(data.as_ref())?;
+		self.streams.take_buffer(data);
+		header.set_rsv1(true);
+		header.set_payload_len(data.as_ref().len());
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::DeflateFrame;
+	use crate::{
+		base::{Header, OpCode},
+		connection::Mode,
+		extension::{
+			tests::{negotiate, send_frame},
+			Extension, Fragment, FrameContext, MessageTooLarge, Param,
+		},
+		Storage,
+	};
+
+	#[test]
+	fn every_frame_is_compressed() {
+		let (mut client, mut server) = negotiate(DeflateFrame::new(Mode::Client), DeflateFrame::new(Mode::Server));
+		assert_eq!("x-webkit-deflate-frame", client.name());
+		let data = b"a frame which is sent several times";
+		let first = send_frame(&mut client, &mut server, FrameContext::new(Fragment::First, OpCode::Text), data);
+		// The sliding window of the previous frame is used.
+		let next = send_frame(&mut client, &mut server, FrameContext::new(Fragment::Continuation, OpCode::Text), data);
+		assert!(next < first, "{} vs. {}", next, first);
+		send_frame(&mut client, &mut server, FrameContext::new(Fragment::Final, OpCode::Text), data);
+		send_frame(&mut server, &mut client, FrameContext::new(Fragment::Unfragmented, OpCode::Binary), data);
+	}
+
+	#[test]
+	fn negotiation() {
+		let mut client = DeflateFrame::new(Mode::Client);
+		client.set_no_context_takeover(true);
+		let mut server = DeflateFrame::new(Mode::Server);
+		server.set_prefixed(false).set_max_window_bits(15).set_no_context_takeover(true);
+		assert_eq!("deflate-frame", server.name());
+		let (mut client, mut server) = negotiate(client, server);
+		assert!(client.our_no_context_takeover && server.our_no_context_takeover);
+
+		let data = b"a frame which is sent several times";
+		let frame = FrameContext::new(Fragment::Unfragmented, OpCode::Text);
+		let first = send_frame(&mut client, &mut server, frame, data);
+		assert_eq!(first, send_frame(&mut client, &mut server, frame, data));
+		assert_eq!(first, send_frame(&mut server, &mut client, frame, data));
+
+		let mut server = DeflateFrame::new(Mode::Server);
+		server.configure(&[Param::new("client_max_window_bits")]).unwrap();
+		assert!(!server.is_enabled());
+		let mut max_window_bits = Param::new("max_window_bits");
+		max_window_bits.set_value(Some("16"));
+		server.configure(&[max_window_bits]).unwrap();
+		assert!(!server.is_enabled());
+	}
+
+	#[test]
+	fn decompressed_size_is_limited() {
+		let (mut client, mut server) = negotiate(DeflateFrame::new(Mode::Client), DeflateFrame::new(Mode::Server));
+		server.set_max_decompressed_size(1000);
+
+		let data = vec![0; 600];
+		send_frame(&mut client, &mut server, FrameContext::new(Fragment::First, OpCode::Binary), &data);
+		let frame = FrameContext::new(Fragment::Final, OpCode::Binary);
+		let mut header = Header::new(OpCode::Continue);
+		let mut storage = Storage::Shared(&data);
+		client.encode_frame(&mut header, &mut storage, frame).unwrap();
+		let mut bytes = storage.as_ref().to_vec();
+		let e = server.decode_frame(&mut header, &mut bytes, frame).unwrap_err();
+		assert!(e.downcast_ref::<MessageTooLarge>().is_some());
+	}
+
+	#[test]
+	fn corrupt_frames_are_not_fatal() {
+		let mut client = DeflateFrame::new(Mode::Client);
+		client.set_no_context_takeover(true);
+		let (mut client, mut server) = negotiate(client, DeflateFrame::new(Mode::Server));
+		let data = b"a frame which is sent several times";
+		let frame = FrameContext::new(Fragment::Unfragmented, OpCode::Text);
+		send_frame(&mut server, &mut client, frame, data);
+		let mut header = Header::new(OpCode::Text);
+		header.set_rsv1(true);
+		assert!(client.decode_frame(&mut header, &mut vec![0xff; 8], frame).is_err());
+		send_frame(&mut server, &mut client, frame, data);
+	}
+}
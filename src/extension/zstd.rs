@@ -0,0 +1,461 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Experimental Zstandard compression extension, `permessage-zstd`.
+//!
+//! This extension is not standardised, so both ends have to use soketto
+//! or a compatible implementation. It works like `permessage-deflate`:
+//! compressed messages have RSV1 set on their initial frame and every
+//! message is compressed on its own into a single Zstandard frame.
+//!
+//! The following parameters are negotiated during handshake:
+//!
+//! - `compression_level`: the level offered by the client. The server
+//!   responds with the level both ends use, which is at most its own
+//!   maximum level.
+//! - `dictionary_id`: an optional dictionary both ends have agreed on in
+//!   advance, which improves the compression of small messages. The server
+//!   declines the offer if it does not know the dictionary.
+//!
+//! Messages sent in fragments are sent uncompressed, i.e. without RSV1.
+
+use crate::{
+	base::{Header, OpCode},
+	connection::Mode,
+	extension::{Extension, MessageTooLarge, Param},
+	BoxedError, Storage,
+};
+use std::{fmt, io, mem};
+use zstd_safe::{CCtx, CParameter, DCtx, InBuffer, OutBuffer, ResetDirective};
+
+const COMPRESSION_LEVEL: &str = "compression_level";
+const DICTIONARY_ID: &str = "dictionary_id";
+
+/// The default compression level of Zstandard.
+const DEFAULT_LEVEL: i32 = 3;
+
+/// The zstd extension type.
+pub struct Zstd {
+	mode: Mode,
+	enabled: bool,
+	buffer: Vec<u8>,
+	params: Vec<Param<'static>>,
+	/// The level offered in client mode, or the maximum level in server mode.
+	level: i32,
+	/// The dictionary offered in client mode, or the known dictionaries in server mode.
+	dictionaries: Vec<(u32, Vec<u8>)>,
+	/// Compressor state kept between messages.
+	compress: Option<CCtx<'static>>,
+	/// Decompressor state kept between messages.
+	decompress: Option<DCtx<'static>>,
+	/// The maximum message size of the connection.
+	max_message_size: usize,
+}
+
+impl fmt::Debug for Zstd {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Zstd")
+			.field("mode", &self.mode)
+			.field("enabled", &self.enabled)
+			.field("params", &self.params)
+			.field("level", &self.level)
+			.field("dictionaries", &self.dictionaries.iter().map(|(id, _)| id).collect::<Vec<_>>())
+			.finish()
+	}
+}
+
+impl Zstd {
+	/// Create a new zstd extension either on client or server side.
+	pub fn new(mode: Mode) -> Self {
+		let mut zstd = Zstd {
+			mode,
+			enabled: false,
+			buffer: Vec::new(),
+			params: Vec::new(),
+			level: DEFAULT_LEVEL,
+			dictionaries: Vec::new(),
+			compress: None,
+			decompress: None,
+			max_message_size: usize::MAX,
+		};
+		zstd.set_params();
+		zstd
+	}
+
+	/// Set the compression level.
+	///
+	/// In client mode, this is the level offered to the server. In server
+	/// mode, this is the maximum level accepted from clients. The value must
+	/// be within the range supported by Zstandard, i.e. at most 22, where
+	/// negative values trade compression for speed. The default is 3.
+	pub fn set_compression_level(&mut self, level: i32) -> &mut Self {
+		assert!(is_valid_level(level), "compression level has to be within the range supported by zstd");
+		self.level = level;
+		self.set_params();
+		self
+	}
+
+	/// Add a pre-shared dictionary with the given ID.
+	///
+	/// In client mode, the dictionary is offered to the server and only one
+	/// dictionary can be added. Several dictionaries can be offered with one
+	/// `Zstd` extension per dictionary, cf. [`Extension`]. In server mode,
+	/// any number of dictionaries can be added; the one offered by a client
+	/// is used if it is known.
+	pub fn add_dictionary(&mut self, id: u32, dictionary: impl Into<Vec<u8>>) -> &mut Self {
+		if self.mode == Mode::Client {
+			assert!(self.dictionaries.is_empty(), "only one dictionary can be offered");
+		}
+		self.dictionaries.push((id, dictionary.into()));
+		self.set_params();
+		self
+	}
+
+	/// Update the parameters offered in client mode.
+	fn set_params(&mut self) {
+		if self.mode == Mode::Server {
+			return;
+		}
+		self.params.clear();
+		let mut level = Param::new(COMPRESSION_LEVEL);
+		level.set_value(Some(self.level.to_string()));
+		self.params.push(level);
+		if let Some((id, _)) = self.dictionaries.first() {
+			let mut x = Param::new(DICTIONARY_ID);
+			x.set_value(Some(id.to_string()));
+			self.params.push(x)
+		}
+	}
+
+	/// Create the compressor and decompressor with the negotiated level and dictionary.
+	fn create_contexts(&mut self, level: i32, dictionary: Option<usize>) -> Result<(), BoxedError> {
+		let mut compress = CCtx::create();
+		let mut decompress = DCtx::create();
+		compress.set_parameter(CParameter::CompressionLevel(level)).map_err(zstd_error)?;
+		if let Some(i) = dictionary {
+			let dictionary = &self.dictionaries[i].1;
+			compress.load_dictionary(dictionary).map_err(zstd_error)?;
+			decompress.load_dictionary(dictionary).map_err(zstd_error)?;
+		}
+		self.compress = Some(compress);
+		self.decompress = Some(decompress);
+		Ok(())
+	}
+
+	/// Decompress the single zstd frame in `data` into `self.buffer`.
+	fn decompress(&mut self, data: &[u8]) -> Result<(), BoxedError> {
+		let decoder = self.decompress.get_or_insert_with(DCtx::create);
+		self.buffer.clear();
+		let mut input = InBuffer::around(data);
+		loop {
+			if self.buffer.len() == self.buffer.capacity() {
+				// Grow no further than one byte beyond the limit, which suffices to detect a violation.
+				let allowance = self.max_message_size.saturating_add(1).saturating_sub(self.buffer.len());
+				self.buffer.reserve_exact(std::cmp::min(std::cmp::max(data.len(), 4096), allowance))
+			}
+			let pos = self.buffer.len();
+			let remaining = decoder
+				.decompress_stream(&mut OutBuffer::around_pos(&mut self.buffer, pos), &mut input)
+				.map_err(zstd_error)?;
+			if self.buffer.len() > self.max_message_size {
+				return Err(MessageTooLarge { current: self.buffer.len(), maximum: self.max_message_size }.into());
+			}
+			if remaining == 0 {
+				break;
+			}
+			if input.pos() == data.len() && self.buffer.len() < self.buffer.capacity() {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete zstd frame").into());
+			}
+		}
+		if input.pos() != data.len() {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, "data after zstd frame").into());
+		}
+		Ok(())
+	}
+
+	/// Replace `data` with the compressed output in `self.buffer`.
+	fn take_buffer(&mut self, data: &mut Storage) {
+		if let Storage::Owned(d) = data {
+			mem::swap(d, &mut self.buffer)
+		} else {
+			*data = Storage::Owned(mem::take(&mut self.buffer))
+		}
+	}
+}
+
+impl Extension for Zstd {
+	fn name(&self) -> &str {
+		"permessage-zstd"
+	}
+
+	fn is_enabled(&self) -> bool {
+		self.enabled
+	}
+
+	fn params(&self) -> &[Param<'_>] {
+		&self.params
+	}
+
+	fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+		self.enabled = false;
+		let mut level = None;
+		let mut dictionary_id = None;
+		for p in params {
+			log::trace!("configure {:?} with: {}", self.mode, p);
+			match p.name() {
+				COMPRESSION_LEVEL => match p.value().map(|s| s.parse::<i32>()) {
+					Some(Ok(v)) if is_valid_level(v) => level = Some(v),
+					_ => {
+						log::debug!("invalid compression_level: {:?}", p.value());
+						return Ok(());
+					}
+				},
+				DICTIONARY_ID => match p.value().map(|s| s.parse::<u32>()) {
+					Some(Ok(v)) => dictionary_id = Some(v),
+					_ => {
+						log::debug!("invalid dictionary_id: {:?}", p.value());
+						return Ok(());
+					}
+				},
+				_ => {
+					log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+					return Ok(());
+				}
+			}
+		}
+
+		let dictionary = match dictionary_id {
+			Some(id) => match self.dictionaries.iter().position(|(i, _)| *i == id) {
+				Some(i) => Some(i),
+				None => {
+					log::debug!("{}: unknown dictionary: {}", self.name(), id);
+					return Ok(());
+				}
+			},
+			None => None,
+		};
+
+		let level = match self.mode {
+			Mode::Server => {
+				// Every offer is considered on its own.
+				self.params.clear();
+				let level = std::cmp::min(level.unwrap_or(DEFAULT_LEVEL), self.level);
+				let mut x = Param::new(COMPRESSION_LEVEL);
+				x.set_value(Some(level.to_string()));
+				self.params.push(x);
+				if let Some(id) = dictionary_id {
+					let mut x = Param::new(DICTIONARY_ID);
+					x.set_value(Some(id.to_string()));
+					self.params.push(x)
+				}
+				level
+			}
+			Mode::Client => level.unwrap_or(self.level),
+		};
+
+		self.create_contexts(level, dictionary)?;
+		self.enabled = true;
+		Ok(())
+	}
+
+	/// Fragments are passed on uncompressed.
+	fn encodes_fragments(&self) -> bool {
+		true
+	}
+
+	fn reserved_bits(&self) -> (bool, bool, bool) {
+		(true, false, false)
+	}
+
+	fn set_max_message_size(&mut self, max: usize) {
+		self.max_message_size = max
+	}
+
+	fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+		if data.is_empty() {
+			return Ok(());
+		}
+
+		match header.opcode() {
+			OpCode::Binary | OpCode::Text if header.is_rsv1() => {
+				log::trace!("zstd: decoding {}", header)
+			}
+			_ => {
+				log::trace!("zstd: not decoding {}", header);
+				return Ok(());
+			}
+		}
+
+		if let Err(e) = self.decompress(data) {
+			// Start over with the next message.
+			if let Some(decoder) = &mut self.decompress {
+				decoder.reset(ResetDirective::SessionOnly).map_err(zstd_error)?;
+			}
+			self.buffer.clear();
+			return Err(e);
+		}
+
+		mem::swap(data, &mut self.buffer);
+		header.set_rsv1(false);
+		header.set_payload_len(data.len());
+		Ok(())
+	}
+
+	fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+		if data.as_ref().is_empty() {
+			return Ok(());
+		}
+
+		if let OpCode::Binary | OpCode::Text = header.opcode() {
+			log::trace!("zstd: encoding {}", header)
+		} else {
+			log::trace!("zstd: not encoding {}", header);
+			return Ok(());
+		}
+
+		let encoder = self.compress.get_or_insert_with(CCtx::create);
+		self.buffer.clear();
+		self.buffer.reserve(zstd_safe::compress_bound(data.as_ref().len()));
+		encoder.compress2(&mut self.buffer, data.as_ref()).map_err(zstd_error)?;
+
+		self.take_buffer(data);
+		header.set_rsv1(true);
+		header.set_payload_len(data.as_ref().len());
+		Ok(())
+	}
+}
+
+/// Is the compression level supported by zstd?
+fn is_valid_level(level: i32) -> bool {
+	(zstd_safe::min_c_level()..=zstd_safe::max_c_level()).contains(&level)
+}
+
+/// Convert a zstd error code into an error.
+fn zstd_error(code: zstd_safe::ErrorCode) -> BoxedError {
+	io::Error::new(io::ErrorKind::Other, zstd_safe::get_error_name(code)).into()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Zstd;
+	use crate::{
+		base::{Header, OpCode},
+		connection::{Event, Mode, Protocol},
+		data::Data,
+		extension::{
+			tests::{negotiate, offer, send},
+			Extension, MessageTooLarge, Param,
+		},
+		Storage,
+	};
+
+	const DICTIONARY: &[u8] = br#"{"jsonrpc":"2.0","method":"subscription","params":{"result":"#;
+
+	#[test]
+	fn negotiation() {
+		let mut client = Zstd::new(Mode::Client);
+		client.set_compression_level(19).add_dictionary(7, DICTIONARY);
+		let mut server = Zstd::new(Mode::Server);
+		server.set_compression_level(9);
+
+		// Unknown dictionaries are declined.
+		offer(&client, &mut server);
+		assert!(!server.is_enabled());
+
+		server.add_dictionary(1, &b"other"[..]).add_dictionary(7, DICTIONARY);
+		let (client, server) = negotiate(client, server);
+		assert_eq!(Some("9"), server.params().iter().find(|p| p.name() == "compression_level").and_then(Param::value));
+		assert_eq!(Some("7"), server.params().iter().find(|p| p.name() == "dictionary_id").and_then(Param::value));
+		assert_eq!(Some("19"), client.params().iter().find(|p| p.name() == "compression_level").and_then(Param::value));
+
+		// A failed renegotiation leaves the extension disabled.
+		let mut server = server;
+		let mut dictionary = Param::new("dictionary_id");
+		dictionary.set_value(Some("8"));
+		server.configure(&[dictionary]).unwrap();
+		assert!(!server.is_enabled());
+	}
+
+	#[test]
+	fn dictionaries_improve_compression() {
+		let message = br#"{"jsonrpc":"2.0","method":"subscription","params":{"result":42}}"#;
+
+		let (mut client, mut server) = negotiate(Zstd::new(Mode::Client), Zstd::new(Mode::Server));
+		let without = send(&mut client, &mut server, message);
+		assert_eq!(without, send(&mut server, &mut client, message));
+
+		let mut client = Zstd::new(Mode::Client);
+		client.add_dictionary(7, DICTIONARY);
+		let mut server = Zstd::new(Mode::Server);
+		server.add_dictionary(7, DICTIONARY);
+		let (mut client, mut server) = negotiate(client, server);
+		let with = send(&mut client, &mut server, message);
+		assert!(with < without / 2, "{} vs. {}", with, without);
+		send(&mut server, &mut client, message);
+	}
+
+	#[test]
+	fn decompressed_size_is_limited() {
+		let (mut client, mut server) = negotiate(Zstd::new(Mode::Client), Zstd::new(Mode::Server));
+		server.set_max_message_size(64 * 1024);
+
+		let message = vec![0; 64 * 1024 + 1];
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(&message);
+		client.encode(&mut header, &mut data).unwrap();
+		assert!(data.as_ref().len() < 1024);
+		let mut data = data.as_ref().to_vec();
+		let e = server.decode(&mut header, &mut data).unwrap_err();
+		assert!(e.downcast_ref::<MessageTooLarge>().is_some());
+		assert!(server.buffer.capacity() <= 64 * 1024 + 1);
+
+		// The decompressor can be used for subsequent messages.
+		send(&mut client, &mut server, &message[..1024]);
+	}
+
+	#[test]
+	fn fragments_are_sent_uncompressed() {
+		let (client, server) = negotiate(Zstd::new(Mode::Client), Zstd::new(Mode::Server));
+		let mut sender = Protocol::new(Mode::Client);
+		sender.add_extensions([Box::new(client) as Box<dyn Extension + Send>]).unwrap();
+		let mut receiver = Protocol::new(Mode::Server);
+		receiver.add_extensions([Box::new(server) as Box<dyn Extension + Send>]).unwrap();
+
+		sender.send_fragment(OpCode::Text, b"hel".to_vec(), false).unwrap();
+		sender.send_fragment(OpCode::Text, b"lo".to_vec(), true).unwrap();
+		let mut frames = Vec::new();
+		while let Some(bytes) = sender.take_output() {
+			frames.extend_from_slice(&bytes)
+		}
+		assert_eq!(0x01, frames[0]);
+
+		receiver.receive_bytes(&frames);
+		let mut message = Vec::new();
+		assert_eq!(Some(Event::Data(Data::Text(5))), receiver.next_event(&mut message).unwrap());
+		assert_eq!(b"hello", &message[..]);
+	}
+
+	#[test]
+	fn invalid_messages_are_rejected() {
+		let (mut client, mut server) = negotiate(Zstd::new(Mode::Client), Zstd::new(Mode::Server));
+		let message = b"All work and no play makes Jack a dull boy.".repeat(10);
+		let mut header = Header::new(OpCode::Binary);
+		let mut data = Storage::Shared(&message);
+		client.encode(&mut header, &mut data).unwrap();
+		let compressed = data.as_ref().to_vec();
+
+		let mut trailing = compressed.clone();
+		trailing.push(0);
+		assert!(server.decode(&mut header.clone(), &mut trailing).is_err());
+		let mut truncated = compressed[..compressed.len() - 1].to_vec();
+		assert!(server.decode(&mut header.clone(), &mut truncated).is_err());
+
+		// The decompressor starts over with the next message.
+		send(&mut client, &mut server, &message);
+	}
+}
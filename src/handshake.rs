@@ -15,19 +15,14 @@ pub mod client;
 pub mod http;
 pub mod server;
 
-use crate::extension::{Extension, Param};
-use base64::Engine;
+use crate::extension::{self, Extension, Param, ReservedBitConflict};
 use bytes::BytesMut;
-use sha1::{Digest, Sha1};
 use std::{fmt, io, str};
 
+pub use crate::base::{generate_accept_key, WebSocketKey};
 pub use client::{Client, ServerResponse};
 pub use server::{ClientRequest, Server};
 
-// Defined in RFC 6455 and used to generate the `Sec-WebSocket-Accept` header
-// in the server handshake response.
-const KEY: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-
 // How many HTTP headers do we support during parsing?
 const MAX_NUM_HEADERS: usize = 32;
 
@@ -46,9 +41,9 @@ fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) ->
 	headers
 		.iter()
 		.filter(|h| h.name.eq_ignore_ascii_case(name))
-		.fold(Ok(State::Init), |result, header| {
-			if let Ok(State::Match) = result {
-				return result;
+		.try_fold(State::Init, |state, header| {
+			if let State::Match = state {
+				return Ok(state);
 			}
 			if str::from_utf8(header.value)?.split(',').any(|v| v.trim().eq_ignore_ascii_case(ours)) {
 				return Ok(State::Match);
@@ -75,26 +70,35 @@ where
 }
 
 // Configure all extensions with parsed parameters.
+//
+// Every extension is configured with the first acceptable entry, i.e. the
+// first one after which it is enabled. Further entries with the same name
+// are ignored. If several extensions have the same name, an entry is tried
+// with each disabled one in order.
 fn configure_extensions(extensions: &mut [Box<dyn Extension + Send>], line: &str) -> Result<(), Error> {
 	for e in line.split(',') {
 		let mut ext_parts = e.split(';');
 		if let Some(name) = ext_parts.next() {
 			let name = name.trim();
-			if let Some(ext) = extensions.iter_mut().find(|x| x.name().eq_ignore_ascii_case(name)) {
-				let mut params = Vec::new();
-				for p in ext_parts {
-					let mut key_value = p.split('=');
-					if let Some(key) = key_value.next().map(str::trim) {
-						let val = key_value.next().map(|v| v.trim().trim_matches('"'));
-						let mut p = Param::new(key);
-						p.set_value(val);
-						params.push(p)
-					}
+			let mut params = Vec::new();
+			for p in ext_parts {
+				let mut key_value = p.split('=');
+				if let Some(key) = key_value.next().map(str::trim) {
+					let val = key_value.next().map(|v| v.trim().trim_matches('"'));
+					let mut p = Param::new(key);
+					p.set_value(val);
+					params.push(p)
+				}
+			}
+			for ext in extensions.iter_mut().filter(|x| x.name().eq_ignore_ascii_case(name) && !x.is_enabled()) {
+				ext.configure(&params).map_err(Error::Extension)?;
+				if ext.is_enabled() {
+					break;
 				}
-				ext.configure(&params).map_err(Error::Extension)?
 			}
 		}
 	}
+	extension::check_reserved_bits(extensions.iter())?;
 	Ok(())
 }
 
@@ -133,27 +137,29 @@ where
 	}
 }
 
-// This function takes a 16 byte key (base64 encoded, and so 24 bytes of input) that is expected via
-// the `Sec-WebSocket-Key` header during a websocket handshake, and writes the response that's expected
-// to be handed back in the response header `Sec-WebSocket-Accept`.
-//
-// The response is a base64 encoding of a 160bit hash. base64 encoding uses 1 ascii character per 6 bits,
-// and 160 / 6 = 26.66 characters. The output is padded with '=' to the nearest 4 characters, so we need 28
-// bytes in total for all of the characters.
-//
-// See https://datatracker.ietf.org/doc/html/rfc6455#section-1.3 for more information on this.
-fn generate_accept_key<'k>(key_base64: &WebSocketKey) -> [u8; 28] {
-	let mut digest = Sha1::new();
-	digest.update(key_base64);
-	digest.update(KEY);
-	let d = digest.finalize();
-
-	let mut output_buf = [0; 28];
-	let n = base64::engine::general_purpose::STANDARD
-		.encode_slice(d, &mut output_buf)
-		.expect("encoding to base64 is exactly 28 bytes; qed");
-	debug_assert_eq!(n, 28, "encoding to base64 should be exactly 28 bytes");
-	output_buf
+// Create the `tracing` span of a server handshake.
+#[cfg(feature = "tracing")]
+fn server_span() -> tracing::Span {
+	tracing::debug_span!(
+		"websocket_handshake",
+		mode = "server",
+		path = tracing::field::Empty,
+		peer = tracing::field::Empty,
+		protocol = tracing::field::Empty,
+		extensions = tracing::field::Empty
+	)
+}
+
+// Record the selected protocol and enabled extensions of an accepted handshake.
+#[cfg(feature = "tracing")]
+fn record_accepted(span: &tracing::Span, protocol: Option<&str>, extensions: &[Box<dyn Extension + Send>]) {
+	if let Some(p) = protocol {
+		span.record("protocol", p);
+	}
+	let names = extensions.iter().filter(|e| e.is_enabled()).map(|e| e.name()).collect::<Vec<_>>();
+	if !names.is_empty() {
+		span.record("extensions", names.join(", ").as_str());
+	}
 }
 
 /// Enumeration of possible handshake errors.
@@ -182,6 +188,8 @@ pub enum Error {
 	UnsolicitedProtocol,
 	/// An extension produced an error while encoding or decoding.
 	Extension(crate::BoxedError),
+	/// Two negotiated extensions use the same reserved bit.
+	ReservedBitConflict(ReservedBitConflict),
 	/// The HTTP entity could not be parsed successfully.
 	Http(crate::BoxedError),
 	/// UTF-8 decoding failed.
@@ -204,6 +212,7 @@ impl fmt::Display for Error {
 			Error::UnsolicitedExtension => f.write_str("unsolicited extension returned"),
 			Error::UnsolicitedProtocol => f.write_str("unsolicited protocol returned"),
 			Error::Extension(e) => write!(f, "extension error: {}", e),
+			Error::ReservedBitConflict(e) => write!(f, "extension conflict: {}", e),
 			Error::Http(e) => write!(f, "http parser error: {}", e),
 			Error::Utf8(e) => write!(f, "utf-8 decoding error: {}", e),
 		}
@@ -215,6 +224,7 @@ impl std::error::Error for Error {
 		match self {
 			Error::Io(e) => Some(e),
 			Error::Extension(e) => Some(&**e),
+			Error::ReservedBitConflict(e) => Some(e),
 			Error::Http(e) => Some(&**e),
 			Error::Utf8(e) => Some(e),
 			Error::UnsupportedHttpVersion
@@ -236,28 +246,96 @@ impl From<io::Error> for Error {
 	}
 }
 
+impl From<ReservedBitConflict> for Error {
+	fn from(e: ReservedBitConflict) -> Self {
+		Error::ReservedBitConflict(e)
+	}
+}
+
 impl From<str::Utf8Error> for Error {
 	fn from(e: str::Utf8Error) -> Self {
 		Error::Utf8(e)
 	}
 }
 
-/// Owned value of the `Sec-WebSocket-Key` header.
-///
-/// Per [RFC 6455](https://datatracker.ietf.org/doc/html/rfc6455#section-4.1):
-///
-/// ```text
-/// (...) The value of this header field MUST be a
-/// nonce consisting of a randomly selected 16-byte value that has
-/// been base64-encoded (see Section 4 of [RFC4648]). (...)
-/// ```
-///
-/// Base64 encoding of the nonce produces 24 ASCII bytes, padding included.
-pub type WebSocketKey = [u8; 24];
-
 #[cfg(test)]
 mod tests {
-	use super::expect_ascii_header;
+	use super::{configure_extensions, expect_ascii_header, Error};
+	use crate::{
+		extension::{Extension, Param},
+		BoxedError,
+	};
+
+	/// An extension using RSV1 which is enabled by negotiation.
+	#[derive(Debug)]
+	struct Rsv1(&'static str, bool);
+
+	impl Extension for Rsv1 {
+		fn is_enabled(&self) -> bool {
+			self.1
+		}
+
+		fn name(&self) -> &str {
+			self.0
+		}
+
+		fn params(&self) -> &[Param<'_>] {
+			&[]
+		}
+
+		fn configure(&mut self, _: &[Param]) -> Result<(), BoxedError> {
+			self.1 = true;
+			Ok(())
+		}
+
+		fn reserved_bits(&self) -> (bool, bool, bool) {
+			(true, false, false)
+		}
+	}
+
+	#[cfg(feature = "deflate")]
+	#[test]
+	fn first_acceptable_offer_is_used() {
+		use crate::{connection::Mode, extension::deflate::Deflate};
+
+		// The server accepts the first offer it supports.
+		let mut extensions: Vec<Box<dyn Extension + Send>> = vec![Box::new(Deflate::new(Mode::Server))];
+		let offers = "permessage-deflate; server_max_window_bits=7, \
+			permessage-deflate; server_max_window_bits=10, permessage-deflate";
+		configure_extensions(&mut extensions, offers).unwrap();
+		assert!(extensions[0].is_enabled());
+		assert_eq!("server_max_window_bits = 10", extensions[0].params()[0].to_string());
+
+		// The client uses the first offer the response is acceptable for.
+		let mut limited = Deflate::new(Mode::Client);
+		limited.set_max_server_window_bits(10);
+		let mut extensions: Vec<Box<dyn Extension + Send>> =
+			vec![Box::new(limited), Box::new(Deflate::new(Mode::Client))];
+		let response = "permessage-deflate; server_no_context_takeover; server_max_window_bits=12";
+		configure_extensions(&mut extensions, response).unwrap();
+		assert!(!extensions[0].is_enabled());
+		assert!(extensions[1].is_enabled());
+	}
+
+	#[test]
+	fn conflicting_extensions_are_refused() {
+		let mut extensions: Vec<Box<dyn Extension + Send>> =
+			vec![Box::new(Rsv1("a", false)), Box::new(Rsv1("b", false))];
+		configure_extensions(&mut extensions, "a").unwrap();
+		configure_extensions(&mut extensions, "a, c").unwrap();
+		match configure_extensions(&mut extensions, "b; x=1") {
+			Err(Error::ReservedBitConflict(e)) => assert_eq!(("a", "b"), e.extensions()),
+			other => panic!("unexpected result: {:?}", other),
+		}
+
+		// Extensions enabled in advance are checked as well, instead of panicking.
+		let mut server = super::Server::new(futures::io::Cursor::new(Vec::new()));
+		server.add_extension(Box::new(Rsv1("a", true))).add_extension(Box::new(Rsv1("b", true)));
+		match server.into_builder().err() {
+			Some(Error::ReservedBitConflict(e)) => assert_eq!(("a", "b"), e.extensions()),
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
 
 	#[test]
 	fn header_match() {
@@ -11,15 +11,18 @@
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
 use super::{
-	append_extensions, configure_extensions, expect_ascii_header, with_first_header, Error, WebSocketKey, KEY,
-	MAX_NUM_HEADERS, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL,
+	append_extensions, configure_extensions, expect_ascii_header, generate_accept_key, with_first_header, Error,
+	WebSocketKey, MAX_NUM_HEADERS, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL,
 };
 use crate::connection::{self, Mode};
-use crate::{extension::Extension, Parsing};
+use crate::{
+	extension::Extension,
+	mask::{MaskSource, ThreadRng},
+	Parsing,
+};
 use base64::Engine;
 use bytes::{Buf, BytesMut};
 use futures::prelude::*;
-use sha1::{Digest, Sha1};
 use std::{mem, str};
 
 pub use httparse::Header;
@@ -39,12 +42,17 @@ pub struct Client<'a, T> {
 	headers: &'a [Header<'a>],
 	/// A buffer holding the base-64 encoded request nonce.
 	nonce: WebSocketKey,
+	/// The source of the request nonce and, later, of frame masks.
+	masks: Box<dyn MaskSource + Send>,
 	/// The protocols to include in the handshake.
 	protocols: Vec<&'a str>,
 	/// The extensions the client wishes to include in the request.
 	extensions: Vec<Box<dyn Extension + Send>>,
 	/// Encoding/decoding buffer.
 	buffer: BytesMut,
+	/// The `tracing` span of this handshake.
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
 }
 
 impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
@@ -56,9 +64,20 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 			resource,
 			headers: &[],
 			nonce: [0; 24],
+			masks: Box::new(ThreadRng::new()),
 			protocols: Vec::new(),
 			extensions: Vec::new(),
 			buffer: BytesMut::new(),
+			#[cfg(feature = "tracing")]
+			span: tracing::debug_span!(
+				"websocket_handshake",
+				mode = "client",
+				host,
+				path = resource,
+				peer = tracing::field::Empty,
+				protocol = tracing::field::Empty,
+				extensions = tracing::field::Empty
+			),
 		}
 	}
 
@@ -81,6 +100,26 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 		self
 	}
 
+	/// Set the source of the request nonce.
+	///
+	/// The source is passed on to the [`connection::Builder`] created by
+	/// [`Client::into_builder`] to generate frame masks.
+	pub fn set_mask_source(&mut self, masks: Box<dyn MaskSource + Send>) -> &mut Self {
+		self.masks = masks;
+		self
+	}
+
+	/// The `tracing` span of this handshake.
+	///
+	/// The span has the fields `mode`, `host`, `path`, `peer`, `protocol` and
+	/// `extensions`. The `peer` field is left empty and can be recorded by the
+	/// caller. The span of the connection created by [`Client::into_builder`]
+	/// is a child of this span.
+	#[cfg(feature = "tracing")]
+	pub fn span(&self) -> &tracing::Span {
+		&self.span
+	}
+
 	/// Add a protocol to be included in the handshake.
 	pub fn add_protocol(&mut self, p: &'a str) -> &mut Self {
 		self.protocols.push(p);
@@ -88,6 +127,12 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 	}
 
 	/// Add an extension to be included in the handshake.
+	///
+	/// Every extension is offered with its own parameters. To offer
+	/// alternatives, e.g. a `permessage-deflate` offer with limited window
+	/// bits and a plain one as fallback, add several extensions with the
+	/// same name in order of preference. The first one which accepts the
+	/// server's response is enabled.
 	pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
 		self.extensions.push(e);
 		self
@@ -110,17 +155,25 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 			crate::read(&mut self.socket, &mut self.buffer, BLOCK_SIZE).await?;
 			if let Parsing::Done { value, offset } = self.decode_response()? {
 				self.buffer.advance(offset);
+				#[cfg(feature = "tracing")]
+				self.trace_response(&value);
 				return Ok(value);
 			}
 		}
 	}
 
 	/// Turn this handshake into a [`connection::Builder`].
-	pub fn into_builder(mut self) -> connection::Builder<T> {
+	///
+	/// Fails with [`Error::ReservedBitConflict`] if two enabled extensions
+	/// use the same reserved bit.
+	pub fn into_builder(mut self) -> Result<connection::Builder<T>, Error> {
+		#[cfg(feature = "tracing")]
+		let _entered = self.span.clone().entered();
 		let mut builder = connection::Builder::new(self.socket, Mode::Client);
 		builder.set_buffer(self.buffer);
-		builder.add_extensions(self.extensions.drain(..));
-		builder
+		builder.set_mask_source(self.masks);
+		builder.add_extensions(self.extensions.drain(..))?;
+		Ok(builder)
 	}
 
 	/// Get out the inner socket of the client.
@@ -128,9 +181,27 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 		self.socket
 	}
 
+	/// Record the outcome of the handshake in our span.
+	#[cfg(feature = "tracing")]
+	fn trace_response(&self, response: &ServerResponse) {
+		match response {
+			ServerResponse::Accepted { protocol } => {
+				super::record_accepted(&self.span, protocol.as_deref(), &self.extensions);
+				tracing::debug!(parent: &self.span, "handshake accepted")
+			}
+			ServerResponse::Redirect { status_code, location } => {
+				tracing::debug!(parent: &self.span, status_code, location = location.as_str(), "handshake redirected")
+			}
+			ServerResponse::Rejected { status_code } => {
+				tracing::debug!(parent: &self.span, status_code, "handshake rejected")
+			}
+		}
+	}
+
 	/// Encode the client handshake as a request, ready to be sent to the server.
 	fn encode_request(&mut self) {
-		let nonce: [u8; 16] = rand::random();
+		let mut nonce = [0; 16];
+		self.masks.fill_bytes(&mut nonce);
 		base64::engine::general_purpose::STANDARD
 			.encode_slice(nonce, &mut self.nonce)
 			.expect("encoding to base64 is exactly 16 bytes; qed");
@@ -193,12 +264,8 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 		expect_ascii_header(response.headers, "Upgrade", "websocket")?;
 		expect_ascii_header(response.headers, "Connection", "upgrade")?;
 
-		with_first_header(&response.headers, "Sec-WebSocket-Accept", |theirs| {
-			let mut digest = Sha1::new();
-			digest.update(&self.nonce);
-			digest.update(KEY);
-			let ours = base64::engine::general_purpose::STANDARD.encode(digest.finalize());
-			if ours.as_bytes() != theirs {
+		with_first_header(response.headers, "Sec-WebSocket-Accept", |theirs| {
+			if generate_accept_key(&self.nonce)[..] != *theirs {
 				return Err(Error::InvalidSecWebSocketAccept);
 			}
 			Ok(())
@@ -247,3 +314,26 @@ pub enum ServerResponse {
 		status_code: u16,
 	},
 }
+
+#[cfg(test)]
+mod tests {
+	use super::Client;
+	use crate::mask::Seeded;
+	use futures::io::Cursor;
+
+	/// Encode a client request with the given seed and return its `Sec-WebSocket-Key`.
+	fn key_with_seed(seed: u64) -> String {
+		let mut client = Client::new(Cursor::new(Vec::new()), "localhost", "/");
+		client.set_mask_source(Box::new(Seeded::new(seed)));
+		client.encode_request();
+		let request = std::str::from_utf8(&client.buffer).unwrap();
+		let line = request.lines().find(|l| l.starts_with("Sec-WebSocket-Key: ")).unwrap();
+		line["Sec-WebSocket-Key: ".len()..].to_string()
+	}
+
+	#[test]
+	fn seeded_nonce_is_deterministic() {
+		assert_eq!(key_with_seed(7), key_with_seed(7));
+		assert_ne!(key_with_seed(7), key_with_seed(8));
+	}
+}
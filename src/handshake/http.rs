@@ -34,12 +34,37 @@ pub struct Server {
 	extensions: Vec<Box<dyn Extension + Send>>,
 	// Encoding/decoding buffer.
 	buffer: BytesMut,
+	// The `tracing` span of this handshake.
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
+}
+
+impl Default for Server {
+	fn default() -> Self {
+		Server::new()
+	}
 }
 
 impl Server {
 	/// Create a new server handshake.
 	pub fn new() -> Self {
-		Server { extensions: Vec::new(), buffer: BytesMut::new() }
+		Server {
+			extensions: Vec::new(),
+			buffer: BytesMut::new(),
+			#[cfg(feature = "tracing")]
+			span: handshake::server_span(),
+		}
+	}
+
+	/// The `tracing` span of this handshake.
+	///
+	/// The span has the fields `mode`, `path`, `peer`, `protocol` and
+	/// `extensions`. The `peer` field is left empty and can be recorded by
+	/// the caller. The span of the connection created by
+	/// [`Server::into_builder`] is a child of this span.
+	#[cfg(feature = "tracing")]
+	pub fn span(&self) -> &tracing::Span {
+		&self.span
 	}
 
 	/// Override the buffer to use for request/response handling.
@@ -67,19 +92,19 @@ impl Server {
 	/// Attempt to interpret the provided [`http::Request`] as a WebSocket Upgrade request. If successful, this
 	/// returns an [`http::Response`] that should be returned to the client to complete the handshake.
 	pub fn receive_request<B>(&mut self, req: &http::Request<B>) -> Result<http::Response<()>, Error> {
-		if !is_upgrade_request(&req) {
+		if !is_upgrade_request(req) {
 			return Err(Error::InvalidSecWebSocketAccept);
 		}
 
 		let key = match req.headers().get("Sec-WebSocket-Key") {
 			Some(key) => key,
 			None => {
-				return Err(Error::HeaderNotFound("Sec-WebSocket-Key".into()).into());
+				return Err(Error::HeaderNotFound("Sec-WebSocket-Key".into()));
 			}
 		};
 
 		if req.headers().get("Sec-WebSocket-Version").map(|v| v.as_bytes()) != Some(b"13") {
-			return Err(Error::HeaderNotFound("Sec-WebSocket-Version".into()).into());
+			return Err(Error::HeaderNotFound("Sec-WebSocket-Version".into()));
 		}
 
 		// Pull out the Sec-WebSocket-Key and generate the appropriate response to it.
@@ -99,7 +124,7 @@ impl Server {
 
 		// Attempt to set the extension configuration params that the client requested.
 		for config_str in &extension_config {
-			handshake::configure_extensions(&mut self.extensions, &config_str)?;
+			handshake::configure_extensions(&mut self.extensions, config_str)?;
 		}
 
 		// Build a response that should be sent back to the client to acknowledge the upgrade.
@@ -118,16 +143,31 @@ impl Server {
 			response = response.header("Sec-WebSocket-Extensions", buf.as_ref());
 		}
 
+		#[cfg(feature = "tracing")]
+		{
+			self.span.record("path", req.uri().path());
+			handshake::record_accepted(&self.span, None, &self.extensions);
+			tracing::debug!(parent: &self.span, "handshake accepted")
+		}
+
 		let response = response.body(()).expect("bug: failed to build response");
 		Ok(response)
 	}
 
 	/// Turn this handshake into a [`connection::Builder`].
-	pub fn into_builder<T: AsyncRead + AsyncWrite + Unpin>(mut self, socket: T) -> connection::Builder<T> {
+	///
+	/// Fails with [`Error::ReservedBitConflict`] if two enabled extensions
+	/// use the same reserved bit.
+	pub fn into_builder<T: AsyncRead + AsyncWrite + Unpin>(
+		mut self,
+		socket: T,
+	) -> Result<connection::Builder<T>, Error> {
+		#[cfg(feature = "tracing")]
+		let _entered = self.span.clone().entered();
 		let mut builder = connection::Builder::new(socket, Mode::Server);
 		builder.set_buffer(self.buffer);
-		builder.add_extensions(self.extensions.drain(..));
-		builder
+		builder.add_extensions(self.extensions.drain(..))?;
+		Ok(builder)
 	}
 }
 
@@ -34,12 +34,22 @@ pub struct Server<'a, T> {
 	extensions: Vec<Box<dyn Extension + Send>>,
 	/// Encoding/decoding buffer.
 	buffer: BytesMut,
+	/// The `tracing` span of this handshake.
+	#[cfg(feature = "tracing")]
+	span: tracing::Span,
 }
 
 impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 	/// Create a new server handshake.
 	pub fn new(socket: T) -> Self {
-		Server { socket, protocols: Vec::new(), extensions: Vec::new(), buffer: BytesMut::new() }
+		Server {
+			socket,
+			protocols: Vec::new(),
+			extensions: Vec::new(),
+			buffer: BytesMut::new(),
+			#[cfg(feature = "tracing")]
+			span: super::server_span(),
+		}
 	}
 
 	/// Override the buffer to use for request/response handling.
@@ -53,6 +63,17 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 		mem::take(&mut self.buffer)
 	}
 
+	/// The `tracing` span of this handshake.
+	///
+	/// The span has the fields `mode`, `path`, `peer`, `protocol` and
+	/// `extensions`. The `peer` field is left empty and can be recorded by
+	/// the caller. The span of the connection created by
+	/// [`Server::into_builder`] is a child of this span.
+	#[cfg(feature = "tracing")]
+	pub fn span(&self) -> &tracing::Span {
+		&self.span
+	}
+
 	/// Add a protocol the server supports.
 	pub fn add_protocol(&mut self, p: &'a str) -> &mut Self {
 		self.protocols.push(p);
@@ -104,6 +125,8 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 
 	/// Respond to the client.
 	pub async fn send_response(&mut self, r: &Response<'_>) -> Result<(), Error> {
+		#[cfg(feature = "tracing")]
+		self.trace_response(r);
 		self.buffer.clear();
 		self.encode_response(r);
 		self.socket.write_all(&self.buffer).await?;
@@ -113,11 +136,16 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 	}
 
 	/// Turn this handshake into a [`connection::Builder`].
-	pub fn into_builder(mut self) -> connection::Builder<T> {
+	///
+	/// Fails with [`Error::ReservedBitConflict`] if two enabled extensions
+	/// use the same reserved bit.
+	pub fn into_builder(mut self) -> Result<connection::Builder<T>, Error> {
+		#[cfg(feature = "tracing")]
+		let _entered = self.span.clone().entered();
 		let mut builder = connection::Builder::new(self.socket, Mode::Server);
 		builder.set_buffer(self.buffer);
-		builder.add_extensions(self.extensions.drain(..));
-		builder
+		builder.add_extensions(self.extensions.drain(..))?;
+		Ok(builder)
 	}
 
 	/// Get out the inner socket of the server.
@@ -126,7 +154,7 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 	}
 
 	// Decode client handshake request.
-	fn decode_request(&mut self) -> Result<ClientRequest, Error> {
+	fn decode_request(&mut self) -> Result<ClientRequest<'_>, Error> {
 		let mut header_buf = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
 		let mut request = httparse::Request::new(&mut header_buf);
 
@@ -142,7 +170,7 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 			return Err(Error::UnsupportedHttpVersion);
 		}
 
-		let host = with_first_header(&request.headers, "Host", Ok)?;
+		let host = with_first_header(request.headers, "Host", Ok)?;
 
 		expect_ascii_header(request.headers, "Upgrade", "websocket")?;
 		expect_ascii_header(request.headers, "Connection", "upgrade")?;
@@ -160,7 +188,7 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 			);
 		let headers = RequestHeaders { host, origin };
 
-		let ws_key = with_first_header(&request.headers, "Sec-WebSocket-Key", |k| {
+		let ws_key = with_first_header(request.headers, "Sec-WebSocket-Key", |k| {
 			WebSocketKey::try_from(k).map_err(|_| Error::SecWebSocketKeyInvalidLength(k.len()))
 		})?;
 
@@ -177,14 +205,34 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 
 		let path = request.path.unwrap_or("/");
 
+		#[cfg(feature = "tracing")]
+		{
+			self.span.record("path", path);
+			tracing::debug!(parent: &self.span, "handshake request received")
+		}
+
 		Ok(ClientRequest { ws_key, protocols, path, headers })
 	}
 
+	// Record the response in our span.
+	#[cfg(feature = "tracing")]
+	fn trace_response(&self, response: &Response<'_>) {
+		match response {
+			Response::Accept { protocol, .. } => {
+				super::record_accepted(&self.span, *protocol, &self.extensions);
+				tracing::debug!(parent: &self.span, "handshake accepted")
+			}
+			Response::Reject { status_code } => {
+				tracing::debug!(parent: &self.span, status_code, "handshake rejected")
+			}
+		}
+	}
+
 	// Encode server handshake response.
 	fn encode_response(&mut self, response: &Response<'_>) {
 		match response {
 			Response::Accept { key, protocol } => {
-				let accept_value = super::generate_accept_key(&key);
+				let accept_value = super::generate_accept_key(key);
 				self.buffer.extend_from_slice(
 					concat![
 						"HTTP/1.1 101 Switching Protocols",
@@ -253,7 +301,7 @@ impl<'a> ClientRequest<'a> {
 	}
 
 	/// Select HTTP headers sent by the client.
-	pub fn headers(&self) -> RequestHeaders {
+	pub fn headers(&self) -> RequestHeaders<'_> {
 		self.headers
 	}
 }
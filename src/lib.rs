@@ -21,13 +21,22 @@
 //! answered transparently they have to be received in the first place, so
 //! calling [`connection::Receiver::receive`] is imperative.
 //!
-//! **Note**: None of the `async` methods are safe to cancel so their `Future`s
-//! must not be dropped unless they return `Poll::Ready`.
+//! **Note**: Apart from the methods of [`connection::Sender`] and
+//! [`connection::Receiver`] other than [`connection::Sender::send_binary_mut`],
+//! none of the `async` methods are safe to cancel so
+//! their `Future`s must not be dropped unless they return `Poll::Ready`. If a
+//! `Sender` future is dropped after its frame has been queued, the frame is
+//! still sent by the next operation on that `Sender`.
+//!
+//! Both halves of a connection also offer `poll_*` methods, e.g.
+//! [`connection::Receiver::poll_receive`] and [`connection::Sender::poll_ready`],
+//! for use in hand-written `Future` or `Stream` implementations.
 //!
 //! # Client example
 //!
 //! ```no_run
 //! # use tokio_util::compat::TokioAsyncReadCompatExt;
+//! # #[cfg(feature = "std")]
 //! # async fn doc() -> Result<(), soketto::BoxedError> {
 //! use soketto::handshake::{Client, ServerResponse};
 //!
@@ -39,7 +48,7 @@
 //!
 //! // And finally we perform the handshake and handle the result.
 //! let (mut sender, mut receiver) = match client.handshake().await? {
-//!     ServerResponse::Accepted { .. } => client.into_builder().finish(),
+//!     ServerResponse::Accepted { .. } => client.into_builder()?.finish(),
 //!     ServerResponse::Redirect { status_code, location } => unimplemented!("follow location URL"),
 //!     ServerResponse::Rejected { status_code } => unimplemented!("handle failure")
 //! };
@@ -63,6 +72,7 @@
 //! ```no_run
 //! # use tokio_util::compat::TokioAsyncReadCompatExt;
 //! # use tokio_stream::{wrappers::TcpListenerStream, StreamExt};
+//! # #[cfg(feature = "std")]
 //! # async fn doc() -> Result<(), soketto::BoxedError> {
 //! use soketto::{handshake::{Server, ClientRequest, server::Response}};
 //!
@@ -84,7 +94,7 @@
 //!     server.send_response(&accept).await?;
 //!
 //!     // And we can finally transition to a websocket connection.
-//!     let (mut sender, mut receiver) = server.into_builder().finish();
+//!     let (mut sender, mut receiver) = server.into_builder()?.finish();
 //!
 //!     let mut data = Vec::new();
 //!     let data_type = receiver.receive_data(&mut data).await?;
@@ -106,6 +116,13 @@
 //! See `examples/hyper_server.rs` from this crate's repository for an example of
 //! starting up a WebSocket server alongside an Hyper HTTP server.
 //!
+//! # `no_std` support
+//!
+//! All connection and handshake functionality requires the `std` feature,
+//! which is enabled by default. Without it, this crate is `no_std` (but
+//! requires `alloc`) and only provides the [`base`] frame codec and
+//! [`base::generate_accept_key`].
+//!
 //! [client]: handshake::Client
 //! [server]: handshake::Server
 //! [Sender]: connection::Sender
@@ -113,21 +130,38 @@
 //! [rfc6455]: https://tools.ietf.org/html/rfc6455
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
+#![cfg_attr(not(feature = "std"), no_std)]
 #![forbid(unsafe_code)]
 
 pub mod base;
+#[cfg(feature = "std")]
 pub mod connection;
+#[cfg(feature = "std")]
 pub mod data;
+#[cfg(feature = "std")]
 pub mod extension;
+#[cfg(feature = "std")]
 pub mod handshake;
+#[cfg(feature = "std")]
+pub mod mask;
 
+#[cfg(feature = "std")]
 use bytes::BytesMut;
-use futures::io::{AsyncRead, AsyncReadExt};
-use std::io;
+#[cfg(feature = "std")]
+use futures::io::AsyncRead;
+#[cfg(feature = "std")]
+use std::{
+	io,
+	pin::Pin,
+	task::{ready, Context, Poll},
+};
 
-pub use connection::{Mode, Receiver, Sender};
+#[cfg(feature = "std")]
+pub use connection::{Mode, Receiver, Sender, SharedSender};
+#[cfg(feature = "std")]
 pub use data::{Data, Incoming};
 
+#[cfg(feature = "std")]
 pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
 /// A parsing result.
@@ -145,6 +179,7 @@ pub enum Parsing<T, N = ()> {
 }
 
 /// A buffer type used for implementing `Extension`s.
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub enum Storage<'a> {
 	/// A read-only shared byte slice.
@@ -155,6 +190,7 @@ pub enum Storage<'a> {
 	Owned(Vec<u8>),
 }
 
+#[cfg(feature = "std")]
 impl AsRef<[u8]> for Storage<'_> {
 	fn as_ref(&self) -> &[u8] {
 		match self {
@@ -173,17 +209,36 @@ const fn as_u64(a: usize) -> u64 {
 }
 
 /// Fill the buffer from the given `AsyncRead` impl with up to `max` bytes.
-async fn read<R>(reader: &mut R, dest: &mut BytesMut, max: usize) -> io::Result<()>
+///
+/// Unless some bytes have been read, `dest` is unchanged.
+#[cfg(feature = "std")]
+fn poll_read<R>(reader: &mut R, cx: &mut Context<'_>, dest: &mut BytesMut, max: usize) -> Poll<io::Result<()>>
 where
 	R: AsyncRead + Unpin,
 {
 	let i = dest.len();
 	dest.resize(i + max, 0u8);
-	let n = reader.read(&mut dest[i..]).await?;
-	dest.truncate(i + n);
+	let result = Pin::new(reader).poll_read(cx, &mut dest[i..]);
+	if let Poll::Ready(Ok(n)) = result {
+		dest.truncate(i + n)
+	} else {
+		dest.truncate(i)
+	}
+	let n = ready!(result)?;
 	if n == 0 {
-		return Err(io::ErrorKind::UnexpectedEof.into());
+		return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
 	}
 	log::trace!("read {} bytes", n);
-	Ok(())
+	Poll::Ready(Ok(()))
+}
+
+/// Fill the buffer from the given `AsyncRead` impl with up to `max` bytes.
+///
+/// If the returned future is dropped before completion, `dest` is unchanged.
+#[cfg(feature = "std")]
+async fn read<R>(reader: &mut R, dest: &mut BytesMut, max: usize) -> io::Result<()>
+where
+	R: AsyncRead + Unpin,
+{
+	futures::future::poll_fn(|cx| poll_read(reader, cx, dest, max)).await
 }
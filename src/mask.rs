@@ -0,0 +1,124 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Sources of randomness for frame masks, handshake nonces and connection IDs.
+//!
+//! By default, [`ThreadRng`] is used everywhere. Tests which need byte-exact
+//! client traffic can provide a [`Seeded`] source to
+//! [`connection::Builder::set_mask_source`](crate::connection::Builder::set_mask_source)
+//! and [`handshake::Client::set_mask_source`](crate::handshake::Client::set_mask_source).
+
+use rand::RngCore;
+use std::fmt;
+
+/// A source of random values.
+///
+/// Client connections draw a fresh masking key for every frame they send,
+/// cf. [RFC 6455, section 5.3](https://tools.ietf.org/html/rfc6455#section-5.3).
+/// Implementations used in production must therefore be unpredictable.
+pub trait MaskSource: fmt::Debug {
+	/// Produce the next 32 bit value, e.g. a frame masking key.
+	fn next_u32(&mut self) -> u32;
+
+	/// Fill the given buffer with random bytes.
+	fn fill_bytes(&mut self, dest: &mut [u8]);
+}
+
+impl<M: MaskSource + ?Sized> MaskSource for Box<M> {
+	fn next_u32(&mut self) -> u32 {
+		(**self).next_u32()
+	}
+
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		(**self).fill_bytes(dest)
+	}
+}
+
+/// The default [`MaskSource`], backed by the thread-local RNG of the `rand` crate.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadRng(());
+
+impl ThreadRng {
+	/// Create a new thread RNG source.
+	pub fn new() -> Self {
+		ThreadRng(())
+	}
+}
+
+impl MaskSource for ThreadRng {
+	fn next_u32(&mut self) -> u32 {
+		rand::random()
+	}
+
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		rand::thread_rng().fill_bytes(dest)
+	}
+}
+
+/// A deterministic [`MaskSource`] for testing.
+///
+/// The same seed always produces the same sequence of values, independent
+/// of platform or crate version, which allows byte-exact comparisons of
+/// client traffic. **It must not be used in production.**
+#[derive(Debug, Clone)]
+pub struct Seeded {
+	state: u64,
+}
+
+impl Seeded {
+	/// Create a new deterministic source from the given seed.
+	pub fn new(seed: u64) -> Self {
+		Seeded { state: seed }
+	}
+
+	// SplitMix64, cf. https://prng.di.unimi.it/splitmix64.c
+	fn next_u64(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^ (z >> 31)
+	}
+}
+
+impl MaskSource for Seeded {
+	fn next_u32(&mut self) -> u32 {
+		(self.next_u64() >> 32) as u32
+	}
+
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		for chunk in dest.chunks_mut(8) {
+			let n = chunk.len();
+			chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..n])
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{MaskSource, Seeded};
+
+	#[test]
+	fn seeded_is_deterministic() {
+		let mut a = Seeded::new(42);
+		let mut b = Seeded::new(42);
+		assert_eq!(a.next_u32(), b.next_u32());
+		let mut x = [0; 13];
+		let mut y = [0; 13];
+		a.fill_bytes(&mut x);
+		b.fill_bytes(&mut y);
+		assert_eq!(x, y);
+		assert_ne!(Seeded::new(1).next_u32(), Seeded::new(2).next_u32())
+	}
+
+	#[test]
+	fn seeded_is_stable() {
+		// SplitMix64 reference output for seed 0 is 0xe220a8397b1dcdaf.
+		assert_eq!(0xe220a839, Seeded::new(0).next_u32())
+	}
+}